@@ -0,0 +1,265 @@
+//! Checkpoints for resuming batch gathers.
+//!
+//! Completed queries are recorded in a manifest in the output directory,
+//! which is always replaced atomically (written to a temporary file and
+//! renamed), so it never lists a query whose output was not completely
+//! written. Outputs are written the same way, with `write_atomic`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use greyhound_core::write_atomic;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::gather::Outcome;
+
+pub const CHECKPOINT_FILE: &str = "completed_queries.json";
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// How often the manifest is saved while queries complete. Queries finished
+/// after the last save are processed again when resuming.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Parameters affecting gather outputs. Resuming with different parameters
+/// would mix incompatible results, so it is refused.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct RunParams {
+    pub index: String,
+    pub template: String,
+    #[serde(default)]
+    pub track_abundance: bool,
+    pub threshold_bp: usize,
+    pub output_format: String,
+    pub output_layout: String,
+    pub picklists: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Completed {
+    /// Output filename, relative to the output directory
+    output: String,
+    outcome: Outcome,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    version: u32,
+    params: RunParams,
+    completed: BTreeMap<String, Completed>,
+}
+
+struct State {
+    manifest: Manifest,
+    last_saved: Instant,
+}
+
+pub struct Checkpoint {
+    path: PathBuf,
+    outdir: PathBuf,
+    state: Mutex<State>,
+}
+
+impl Checkpoint {
+    /// Start a checkpoint in `outdir`. If `resume` is set, queries completed
+    /// by a previous run with the same `params` are kept, otherwise any
+    /// previous manifest is replaced.
+    pub fn open(outdir: &Path, params: RunParams, resume: bool) -> Result<Checkpoint, Error> {
+        let path = outdir.join(CHECKPOINT_FILE);
+
+        let manifest = if resume && path.exists() {
+            let manifest: Manifest = serde_json::from_reader(BufReader::new(File::open(&path)?))?;
+            if manifest.version != CHECKPOINT_VERSION {
+                return Err(Error::Checkpoint(format!(
+                    "unsupported version {} in {:?}",
+                    manifest.version, path
+                )));
+            }
+            if manifest.params != params {
+                return Err(Error::Checkpoint(format!(
+                    "{:?} was created with different parameters ({:?})",
+                    path, manifest.params
+                )));
+            }
+            info!(
+                "Resuming, {} queries already completed",
+                manifest.completed.len()
+            );
+            manifest
+        } else {
+            Manifest {
+                version: CHECKPOINT_VERSION,
+                params,
+                completed: BTreeMap::new(),
+            }
+        };
+
+        let checkpoint = Checkpoint {
+            path,
+            outdir: outdir.into(),
+            state: Mutex::new(State {
+                manifest,
+                last_saved: Instant::now(),
+            }),
+        };
+        checkpoint.save()?;
+        Ok(checkpoint)
+    }
+
+    /// The output path and outcome of `query` in a previous run, if it
+    /// completed and its output is still present.
+    pub fn completed(&self, query: &Path) -> Option<(PathBuf, Outcome)> {
+        let state = self.state.lock().unwrap();
+        let completed = state
+            .manifest
+            .completed
+            .get(query.to_string_lossy().as_ref())?;
+        let output = self.outdir.join(&completed.output);
+        if output.is_file() {
+            Some((output, completed.outcome))
+        } else {
+            None
+        }
+    }
+
+    /// Record that the results for `query` were saved to `output`.
+    pub fn mark(&self, query: &Path, output: &Path, outcome: Outcome) -> Result<(), Error> {
+        let output = output.strip_prefix(&self.outdir).unwrap_or(output);
+
+        let mut state = self.state.lock().unwrap();
+        state.manifest.completed.insert(
+            query.to_string_lossy().into(),
+            Completed {
+                output: output.to_string_lossy().into(),
+                outcome,
+            },
+        );
+        if state.last_saved.elapsed() >= SAVE_INTERVAL {
+            self.write(&mut state)?;
+        }
+        Ok(())
+    }
+
+    /// Save the manifest, including all queries marked so far.
+    pub fn save(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        self.write(&mut state)
+    }
+
+    fn write(&self, state: &mut State) -> Result<(), Error> {
+        write_atomic(&self.path, |out| -> Result<(), Error> {
+            serde_json::to_writer(out, &state.manifest)?;
+            Ok(())
+        })?;
+        state.last_saved = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(threshold_bp: usize) -> RunParams {
+        RunParams {
+            index: "index.bin".into(),
+            template: "k=31 scaled=1000 moltype=DNA".into(),
+            track_abundance: false,
+            threshold_bp,
+            output_format: "Plain".into(),
+            output_layout: "Flat".into(),
+            picklists: vec![],
+        }
+    }
+
+    fn saved(outdir: &Path) -> Manifest {
+        let rdr = BufReader::new(File::open(outdir.join(CHECKPOINT_FILE)).unwrap());
+        serde_json::from_reader(rdr).unwrap()
+    }
+
+    #[test]
+    fn resume_with_same_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.sig.csv");
+        std::fs::write(&output, "").unwrap();
+
+        let checkpoint = Checkpoint::open(dir.path(), params(50000), false).unwrap();
+        checkpoint
+            .mark(Path::new("queries/a.sig"), &output, Outcome::Done)
+            .unwrap();
+        checkpoint.save().unwrap();
+
+        let resumed = Checkpoint::open(dir.path(), params(50000), true).unwrap();
+        assert_eq!(
+            resumed.completed(Path::new("queries/a.sig")),
+            Some((output, Outcome::Done))
+        );
+        assert_eq!(resumed.completed(Path::new("queries/b.sig")), None);
+
+        // Without --resume previous results are discarded
+        let restarted = Checkpoint::open(dir.path(), params(50000), false).unwrap();
+        assert_eq!(restarted.completed(Path::new("queries/a.sig")), None);
+    }
+
+    #[test]
+    fn refuse_different_params() {
+        let dir = tempfile::tempdir().unwrap();
+        Checkpoint::open(dir.path(), params(50000), false).unwrap();
+
+        match Checkpoint::open(dir.path(), params(10000), true) {
+            Err(Error::Checkpoint(_)) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("resumed with different parameters"),
+        }
+    }
+
+    #[test]
+    fn skip_missing_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("a.sig.csv");
+
+        let checkpoint = Checkpoint::open(dir.path(), params(50000), false).unwrap();
+        checkpoint
+            .mark(Path::new("a.sig"), &output, Outcome::Empty)
+            .unwrap();
+
+        // Outputs removed after the run are computed again
+        assert_eq!(checkpoint.completed(Path::new("a.sig")), None);
+        std::fs::write(&output, "").unwrap();
+        assert_eq!(
+            checkpoint.completed(Path::new("a.sig")),
+            Some((output, Outcome::Empty))
+        );
+
+        // Outputs are recorded relative to the output directory
+        checkpoint.save().unwrap();
+        assert_eq!(saved(dir.path()).completed["a.sig"].output, "a.sig.csv");
+    }
+
+    #[test]
+    fn save_after_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = Checkpoint::open(dir.path(), params(50000), false).unwrap();
+
+        let output = dir.path().join("a.sig.csv");
+        checkpoint
+            .mark(Path::new("a.sig"), &output, Outcome::Done)
+            .unwrap();
+        assert!(saved(dir.path()).completed.is_empty());
+
+        checkpoint.state.lock().unwrap().last_saved = Instant::now() - SAVE_INTERVAL;
+        let output = dir.path().join("b.sig.csv");
+        checkpoint
+            .mark(Path::new("b.sig"), &output, Outcome::Done)
+            .unwrap();
+        let completed = saved(dir.path()).completed;
+        assert_eq!(completed.len(), 2);
+        assert!(completed.contains_key("a.sig"));
+        assert!(completed.contains_key("b.sig"));
+    }
+}
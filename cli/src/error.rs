@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No sketch compatible with the index template in {0:?}")]
+    NoCompatibleSketch(PathBuf),
+
+    #[error("Error sketching {path:?}: {reason}")]
+    Sketching { path: PathBuf, reason: String },
+
+    #[error("Query {path:?} failed: {source}")]
+    Query {
+        path: PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("Invalid taxonomy ({0})")]
+    Taxonomy(String),
+
+    #[error(
+        "Queries {first:?} and {second:?} would both save results to {output:?}, \
+         use a different --output-layout"
+    )]
+    OutputCollision {
+        output: PathBuf,
+        first: PathBuf,
+        second: PathBuf,
+    },
+
+    #[error("Can't merge {path:?}: {source}")]
+    Merge {
+        path: PathBuf,
+        #[source]
+        source: greyhound_core::Error,
+    },
+
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    #[error("Can't resume: {0}")]
+    Checkpoint(String),
+
+    #[error("{0} queries failed")]
+    FailedQueries(usize),
+
+    #[error(transparent)]
+    Index(#[from] greyhound_core::Error),
+
+    #[error(transparent)]
+    Sourmash(#[from] sourmash::Error),
+
+    #[error(transparent)]
+    Niffler(#[from] niffler::Error),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
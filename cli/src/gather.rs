@@ -0,0 +1,491 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use greyhound_core::{
+    describe_template, list_queries, write_atomic, GatherResult, Picklist, RevIndex,
+};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::checkpoint::{Checkpoint, RunParams};
+use crate::error::Error;
+use crate::layout::{common_base, OutputLayout, OutputNames};
+use crate::output::{write_combined_gather_results, write_gather_results, GatherRow, OutputFormat};
+use crate::progress::{peak_memory, Progress, RunSummary, Timings};
+use crate::stream::{gather_stdin, STDIN};
+use crate::{load_named_query, open_index, resolve_index, IndexOptions};
+
+/// Options for `gather`, matching the CLI flags.
+#[derive(Debug)]
+pub struct GatherOptions {
+    pub threshold_bp: usize,
+    pub from_file: bool,
+    pub lazy: bool,
+    pub preload: bool,
+    pub mmap: bool,
+    /// Memory budget in bytes for reference signatures loaded during gather
+    pub max_memory: Option<usize>,
+    pub output_format: OutputFormat,
+    pub output_layout: OutputLayout,
+    pub keep_going: bool,
+    pub fail_on_error: bool,
+    pub resume: bool,
+    pub picklists: Vec<Picklist>,
+}
+
+/// A query that failed or had no results, saved in the run summaries.
+#[derive(Serialize, Debug)]
+pub(crate) struct QueryRecord {
+    pub(crate) query: String,
+    pub(crate) reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Done,
+    /// The query had no hashes at the template ksize/scaled.
+    Empty,
+}
+
+pub fn gather<P: AsRef<Path>>(
+    queries_file: P,
+    siglist: P,
+    template: Sketch,
+    output: Option<P>,
+    opts: &GatherOptions,
+) -> Result<(), Error> {
+    if queries_file.as_ref() == Path::new(STDIN) {
+        if output.is_some() {
+            warn!("Results for queries from stdin are written to stdout, ignoring --output");
+        }
+        return gather_stdin(siglist.as_ref(), template, opts);
+    }
+
+    let start = Instant::now();
+    let timings = Timings::default();
+
+    // Prebuilt indices define the template used for queries
+    let (index_path, template) = if opts.from_file {
+        (siglist.as_ref().into(), template)
+    } else {
+        resolve_index(siglist.as_ref(), &template)?
+    };
+
+    let outdir: PathBuf = if let Some(p) = output {
+        p.as_ref().into()
+    } else {
+        let mut path = PathBuf::new();
+        path.push("outputs");
+        path
+    };
+    std::fs::create_dir_all(&outdir)?;
+
+    let combined = opts.output_layout == OutputLayout::Combined;
+    if combined && opts.resume {
+        return Err(Error::Unsupported(
+            "--resume can't be used with --output-layout combined".into(),
+        ));
+    }
+
+    let params = RunParams {
+        index: siglist.as_ref().to_string_lossy().into(),
+        template: describe_template(&template),
+        track_abundance: match &template {
+            Sketch::MinHash(mh) => mh.track_abundance(),
+            _ => false,
+        },
+        threshold_bp: opts.threshold_bp,
+        output_format: format!("{:?}", opts.output_format),
+        output_layout: format!("{:?}", opts.output_layout),
+        picklists: opts.picklists.iter().map(|p| p.to_string()).collect(),
+    };
+    let checkpoint = Checkpoint::open(&outdir, params, opts.resume)?;
+
+    info!("Loading queries");
+
+    let queries_path = list_queries(queries_file, Some(&template))?;
+
+    let base = common_base(&queries_path);
+    let outputs = OutputNames::default();
+    let output_path = |i: usize, sketch: Option<(&str, &str)>| -> Option<PathBuf> {
+        let name = opts
+            .output_layout
+            .output_name(&queries_path[i], &base, sketch)?;
+        let mut path = outdir.join(name);
+        if let Some(ext) = opts.output_format.extension() {
+            let mut filename = path.file_name()?.to_os_string();
+            filename.push(".");
+            filename.push(ext);
+            path.set_file_name(filename);
+        }
+        Some(path)
+    };
+    // Fail before doing any work if two queries would overwrite each other
+    if opts.output_layout.from_paths() {
+        for (i, query_path) in queries_path.iter().enumerate() {
+            if let Some(path) = output_path(i, None) {
+                outputs.claim(&path, query_path)?;
+            }
+        }
+    }
+
+    // Queries completed by a previous run are neither loaded nor gathered
+    // again, but their outputs are still claimed: with the md5 and name
+    // layouts they are only known from the checkpoint
+    let mut resumed: Vec<Option<Outcome>> = vec![None; queries_path.len()];
+    for (i, query_path) in queries_path.iter().enumerate() {
+        if let Some((path, outcome)) = checkpoint.completed(query_path) {
+            outputs.claim(&path, query_path)?;
+            resumed[i] = Some(outcome);
+        }
+    }
+    let n_resumed = resumed.iter().filter(|r| r.is_some()).count();
+    if n_resumed > 0 {
+        info!("Skipping {} queries completed previously", n_resumed);
+    }
+
+    let mut failures = vec![];
+    // Queries loaded upfront, and their position in `queries` for each path
+    let mut queries = vec![];
+    let mut query_names = vec![];
+    let mut query_pos = vec![None; queries_path.len()];
+    let mut threshold = usize::max_value();
+    let eager = !opts.lazy || opts.from_file;
+    let load_start = Instant::now();
+    if eager {
+        let loaded: Vec<_> = queries_path
+            .par_iter()
+            .zip(resumed.par_iter())
+            .map(|(query_path, resumed)| match resumed {
+                Some(_) => None,
+                None => Some(load_named_query(query_path, &template)),
+            })
+            .collect();
+
+        for (i, query) in loaded.into_iter().enumerate() {
+            match query {
+                None => {}
+                Some(Ok((name, q))) => {
+                    // Empty queries are reported later, but don't affect the threshold
+                    if q.size() > 0 {
+                        threshold = cmp::min(threshold, gather_threshold(opts.threshold_bp, &q));
+                    }
+                    let md5 = q.md5sum();
+                    if let Some(path) = output_path(i, Some((name.as_str(), md5.as_str()))) {
+                        outputs.claim(&path, &queries_path[i])?;
+                    }
+                    query_pos[i] = Some(queries.len());
+                    queries.push(q);
+                    query_names.push(name);
+                }
+                Some(Err(e)) => {
+                    let e = query_error(&queries_path[i], e);
+                    if !opts.keep_going {
+                        return Err(e);
+                    }
+                    warn!("{}", e);
+                    failures.push((i, e));
+                }
+            }
+        }
+    }
+
+    timings.add("query_load", load_start.elapsed());
+    info!("Loaded {} query signatures", queries_path.len());
+
+    // Step 1: filter and prepare a reduced RevIndex for all queries
+    let index_start = Instant::now();
+    let index_opts = IndexOptions {
+        from_file: opts.from_file,
+        mmap: opts.mmap,
+        queries: if eager { Some(&queries[..]) } else { None },
+        threshold,
+        keep_sigs: opts.preload && opts.max_memory.is_none(),
+        picklists: &opts.picklists,
+    };
+    let (mut revindex, picked) = open_index(&index_path, &template, &index_opts)?;
+    if let Some(max_memory) = opts.max_memory {
+        if opts.preload {
+            warn!("--preload is ignored with --max-memory, signatures are kept as they are used");
+        }
+        revindex.set_cache(max_memory);
+    }
+    timings.add("index_load", index_start.elapsed());
+
+    // Results for all queries, if they are saved in one file
+    let combined_rows: Mutex<Vec<(usize, Vec<GatherRow>)>> = Mutex::new(vec![]);
+
+    // Step 2: Gather using the RevIndex and a specific Counter for each query
+    let process_query = |i: usize| -> Result<Outcome, Error> {
+        let query_path = &queries_path[i];
+        let (name, query) = match query_pos[i] {
+            Some(pos) => (
+                Cow::Borrowed(query_names[pos].as_str()),
+                Cow::Borrowed(&queries[pos]),
+            ),
+            None => {
+                let (name, query) =
+                    timings.time("query_load", || load_named_query(query_path, &template))?;
+                (Cow::Owned(name), Cow::Owned(query))
+            }
+        };
+
+        let (matches, outcome) = gather_query(
+            &revindex,
+            picked.as_deref(),
+            &query,
+            opts.threshold_bp,
+            &timings,
+        )?;
+        if outcome == Outcome::Empty {
+            debug!("Query {:?} has no hashes, skipped gather", query_path);
+        }
+
+        if combined {
+            let key = query_path.to_string_lossy();
+            let rows = matches
+                .iter()
+                .map(|m| GatherRow::from(m).with_query(key.to_string()))
+                .collect();
+            combined_rows.lock().unwrap().push((i, rows));
+        } else {
+            let md5 = query.md5sum();
+            let path = output_path(i, Some((&*name, md5.as_str()))).ok_or_else(|| {
+                Error::Unsupported(format!("no output name for query {:?}", query_path))
+            })?;
+            outputs.claim(&path, query_path)?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            debug!("Saving {} matches", matches.len());
+            timings.time("write_results", || {
+                write_atomic(&path, |out| {
+                    write_gather_results(out, &matches, opts.output_format)
+                })
+            })?;
+            checkpoint.mark(query_path, &path, outcome)?;
+        }
+        debug!("Finishing query {:?}", query_path);
+        Ok(outcome)
+    };
+
+    // Queries that failed to load upfront are not retried
+    let pending: Vec<usize> = (0..queries_path.len())
+        .filter(|i| resumed[*i].is_none() && (!eager || query_pos[*i].is_some()))
+        .collect();
+
+    let progress = Progress::new(pending.len());
+    let run_query = |i: usize| -> Result<Outcome, Error> {
+        let result = process_query(i);
+        progress.inc(result.is_ok());
+        result
+    };
+
+    let outcomes: Result<Vec<(usize, Outcome)>, Error> = if opts.keep_going {
+        let results: Vec<_> = pending.par_iter().map(|&i| (i, run_query(i))).collect();
+
+        let mut outcomes = vec![];
+        for (i, result) in results {
+            match result {
+                Ok(outcome) => outcomes.push((i, outcome)),
+                Err(e) => {
+                    let e = query_error(&queries_path[i], e);
+                    warn!("{}", e);
+                    failures.push((i, e));
+                }
+            }
+        }
+        Ok(outcomes)
+    } else {
+        pending
+            .par_iter()
+            .map(|&i| {
+                run_query(i)
+                    .map(|outcome| (i, outcome))
+                    .map_err(|e| query_error(&queries_path[i], e))
+            })
+            .collect()
+    };
+    progress.finish();
+    // Save queries completed before a failure too, so they can be resumed
+    checkpoint.save()?;
+    let outcomes = outcomes?;
+
+    if combined {
+        let mut rows = combined_rows.into_inner().unwrap();
+        rows.sort_unstable_by_key(|(i, _)| *i);
+        let rows: Vec<GatherRow> = rows.into_iter().flat_map(|(_, rows)| rows).collect();
+
+        let ext = opts.output_format.extension().unwrap_or("txt");
+        let path = outdir.join(format!("gather_results.{}", ext));
+        timings.time("write_results", || {
+            write_atomic(&path, |out| {
+                write_combined_gather_results(out, &rows, opts.output_format)
+            })
+        })?;
+        info!("Saved results for all queries to {:?}", path);
+    }
+
+    let mut empty: Vec<usize> = outcomes
+        .into_iter()
+        .chain(
+            resumed
+                .iter()
+                .enumerate()
+                .filter_map(|(i, r)| r.map(|outcome| (i, outcome))),
+        )
+        .filter_map(|(i, outcome)| match outcome {
+            Outcome::Empty => Some(i),
+            Outcome::Done => None,
+        })
+        .collect();
+
+    if let Some(stats) = revindex.cache_stats() {
+        info!(
+            "Signature cache: {} hits, {} misses, {} evictions",
+            stats.hits(),
+            stats.misses(),
+            stats.evictions()
+        );
+    }
+    info!("Finished");
+
+    if !empty.is_empty() {
+        empty.sort_unstable();
+        let reason = empty_reason(&template);
+
+        let mut path = outdir.clone();
+        path.push("empty_queries.csv");
+        let records = empty.iter().map(|i| QueryRecord {
+            query: queries_path[*i].to_string_lossy().into(),
+            reason: reason.clone(),
+        });
+        write_records(&path, records)?;
+
+        warn!(
+            "{} of {} queries had {}, see {:?}",
+            empty.len(),
+            queries_path.len(),
+            reason,
+            path
+        );
+    }
+
+    let summary = RunSummary {
+        queries: queries_path.len(),
+        done: progress.done(),
+        failed: failures.len(),
+        empty: empty.len(),
+        resumed: n_resumed,
+        wall_time_secs: start.elapsed().as_secs_f64(),
+        peak_memory_bytes: peak_memory(),
+        phases: timings.as_secs(),
+    };
+    summary.save(&outdir)?;
+
+    if opts.keep_going {
+        let mut path = outdir;
+        path.push("failed_queries.csv");
+        write_failures(&path, &queries_path, &mut failures)?;
+
+        if !failures.is_empty() {
+            warn!(
+                "{} of {} queries failed, see {:?}",
+                failures.len(),
+                queries_path.len(),
+                path
+            );
+            if opts.fail_on_error {
+                return Err(Error::FailedQueries(failures.len()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Gather `query`, only counting `picked` datasets if set. Queries without
+/// hashes are not gathered, and have an `Empty` outcome.
+pub(crate) fn gather_query(
+    revindex: &RevIndex,
+    picked: Option<&[bool]>,
+    query: &KmerMinHash,
+    threshold_bp: usize,
+    timings: &Timings,
+) -> Result<(Vec<GatherResult>, Outcome), Error> {
+    if query.size() == 0 {
+        return Ok((vec![], Outcome::Empty));
+    }
+
+    debug!("Build counter for query");
+    let counter = timings.time("counter_build", || revindex.counter_for(query, picked));
+    let threshold = gather_threshold(threshold_bp, query);
+
+    debug!("Starting gather");
+    let matches = timings.time("gather", || revindex.gather(counter, threshold, query))?;
+    Ok((matches, Outcome::Done))
+}
+
+/// Reason recorded for queries without hashes at the `template` parameters.
+pub(crate) fn empty_reason(template: &Sketch) -> String {
+    format!("no hashes at {}", describe_template(template))
+}
+
+/// Threshold for `RevIndex::gather` from `--threshold-bp`, for `query`.
+/// Queries without hashes have no threshold.
+fn gather_threshold(threshold_bp: usize, query: &KmerMinHash) -> usize {
+    match query.size() {
+        0 => 0,
+        size => threshold_bp / (size * query.scaled() as usize),
+    }
+}
+
+pub(crate) fn query_error(path: &Path, e: Error) -> Error {
+    Error::Query {
+        path: path.into(),
+        source: Box::new(e),
+    }
+}
+
+pub(crate) fn write_failures(
+    path: &Path,
+    queries_path: &[PathBuf],
+    failures: &mut Vec<(usize, Error)>,
+) -> Result<(), Error> {
+    failures.sort_by_key(|(i, _)| *i);
+
+    let records = failures.iter().map(|(i, e)| {
+        let reason = match e {
+            Error::Query { source, .. } => source.to_string(),
+            e => e.to_string(),
+        };
+        QueryRecord {
+            query: queries_path[*i].to_string_lossy().into(),
+            reason,
+        }
+    });
+    write_records(path, records)
+}
+
+fn write_records<I: Iterator<Item = QueryRecord>>(path: &Path, records: I) -> Result<(), Error> {
+    write_atomic(path, |out| {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(out);
+        wtr.write_record(&["query", "reason"])?;
+        for record in records {
+            wtr.serialize(record)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    })
+}
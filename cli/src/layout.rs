@@ -0,0 +1,192 @@
+//! Naming per-query output files.
+//!
+//! Queries from different directories can have the same filename, so the
+//! output names are checked for collisions before any results are written.
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::error::Error;
+
+/// Maximum length for output names built from signature names.
+const MAX_NAME_LEN: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputLayout {
+    /// Query filename, in the output directory
+    Flat,
+    /// Query path relative to the common directory of all queries
+    Mirror,
+    /// md5 of the query sketch
+    Md5,
+    /// Query signature name, with characters unsafe in filenames replaced
+    Name,
+    /// One file with the results for all queries
+    Combined,
+}
+
+impl OutputLayout {
+    pub const VARIANTS: &'static [&'static str] = &["flat", "mirror", "md5", "name", "combined"];
+
+    /// Output name for the results of a query, relative to the output
+    /// directory and without the format extension.
+    ///
+    /// `base` is the common directory of all queries, from `common_base`.
+    /// The `md5` and `name` layouts need the query name and sketch md5 in
+    /// `sketch`, and `None` is returned if they are not available (as it is
+    /// for the `combined` layout).
+    pub fn output_name(
+        &self,
+        query_path: &Path,
+        base: &Path,
+        sketch: Option<(&str, &str)>,
+    ) -> Option<PathBuf> {
+        match (self, sketch) {
+            (OutputLayout::Flat, _) => Some(query_path.file_name()?.into()),
+            (OutputLayout::Mirror, _) => {
+                let relative = query_path.strip_prefix(base).unwrap_or(query_path);
+                Some(relative_components(relative))
+            }
+            (OutputLayout::Md5, Some((_, md5))) => Some(md5.into()),
+            (OutputLayout::Name, Some((name, _))) => Some(sanitize(name).into()),
+            _ => None,
+        }
+    }
+
+    /// Check if output names depend only on query paths, so collisions can
+    /// be found before loading queries.
+    pub fn from_paths(&self) -> bool {
+        matches!(self, OutputLayout::Flat | OutputLayout::Mirror)
+    }
+
+    /// Fail for the `combined` layout, in commands saving one output per
+    /// query.
+    pub fn require_per_query(&self, command: &str) -> Result<(), Error> {
+        if *self == OutputLayout::Combined {
+            return Err(Error::Unsupported(format!(
+                "{} saves one output per query, --output-layout combined can't be used",
+                command
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for OutputLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "flat" => Ok(OutputLayout::Flat),
+            "mirror" => Ok(OutputLayout::Mirror),
+            "md5" => Ok(OutputLayout::Md5),
+            "name" => Ok(OutputLayout::Name),
+            "combined" => Ok(OutputLayout::Combined),
+            _ => Err(format!("unknown output layout: {}", s)),
+        }
+    }
+}
+
+/// Output paths claimed by each query.
+#[derive(Default)]
+pub struct OutputNames {
+    claimed: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+impl OutputNames {
+    /// Reserve `output` for the results of `query`, failing if another query
+    /// already uses it. Claiming the same output again for a query is fine.
+    pub fn claim(&self, output: &Path, query: &Path) -> Result<(), Error> {
+        let mut claimed = self.claimed.lock().unwrap();
+        match claimed.get(output) {
+            Some(other) if other != query => Err(Error::OutputCollision {
+                output: output.into(),
+                first: other.clone(),
+                second: query.into(),
+            }),
+            Some(_) => Ok(()),
+            None => {
+                claimed.insert(output.into(), query.into());
+                Ok(())
+            }
+        }
+    }
+
+    /// Output path in `outdir` for the results of `query`, named by `layout`
+    /// and without any extension, and claim it for `query`.
+    pub fn claim_stem(
+        &self,
+        layout: OutputLayout,
+        outdir: &Path,
+        base: &Path,
+        query: &Path,
+        sketch: Option<(&str, &str)>,
+    ) -> Result<PathBuf, Error> {
+        let name = layout
+            .output_name(query, base, sketch)
+            .ok_or_else(|| Error::Unsupported(format!("no output name for query {:?}", query)))?;
+        let stem = outdir.join(name);
+        self.claim(&stem, query)?;
+        Ok(stem)
+    }
+}
+
+/// `stem` with `suffix` appended to its filename.
+pub fn with_suffix(stem: &Path, suffix: &str) -> PathBuf {
+    let mut path = stem.as_os_str().to_os_string();
+    path.push(suffix);
+    path.into()
+}
+
+/// The deepest directory containing all `paths`.
+pub fn common_base(paths: &[PathBuf]) -> PathBuf {
+    let mut parents = paths
+        .iter()
+        .map(|p| p.parent().unwrap_or_else(|| Path::new("")));
+    let first = match parents.next() {
+        Some(first) => first,
+        None => return PathBuf::new(),
+    };
+
+    parents.fold(first.to_path_buf(), |base, parent| {
+        base.components()
+            .zip(parent.components())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a)
+            .collect()
+    })
+}
+
+/// Keep `path` inside the output directory: root and `.` components are
+/// dropped, and `..` is replaced.
+fn relative_components(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(c) => Some(c),
+            Component::ParentDir => Some(OsStr::new("__")),
+            _ => None,
+        })
+        .collect()
+}
+
+fn sanitize(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_NAME_LEN)
+        .collect();
+    // Avoid hidden files and `.`/`..`
+    match name.trim_start_matches('.') {
+        "" => "unnamed".into(),
+        name => name.into(),
+    }
+}
@@ -1,26 +1,55 @@
-use std::cmp;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 
-use log::info;
+use log::{info, warn};
 use structopt::StructOpt;
 
+use greyhound_core::{
+    is_sequence_file, list_queries, list_signatures, load_signatures, select_minhash, IndexFormat,
+    MultiIndex, Picklist, RevIndex, TemplateEntry,
+};
 use rayon::prelude::*;
-use sourmash::index::greyhound::RevIndex;
-use sourmash::signature::{Signature, SigsTrait};
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::Signature;
 use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
 use sourmash::sketch::Sketch;
 
+mod checkpoint;
+mod error;
+mod gather;
+mod layout;
+mod manage;
+mod output;
+mod prefetch;
+mod progress;
+mod search;
+mod sketch;
+mod stream;
+mod subtract;
+mod tax;
+
+use crate::error::Error;
+use crate::gather::{query_error, write_failures, GatherOptions};
+use crate::layout::{common_base, OutputLayout, OutputNames};
+use crate::output::OutputFormat;
+use crate::prefetch::PrefetchOptions;
+use crate::search::SearchOptions;
+use crate::subtract::SubtractOptions;
+use crate::tax::TaxFormat;
+
 #[derive(StructOpt, Debug)]
 enum Cli {
     Gather {
-        /// Query signature
+        /// Query signatures or FASTA/FASTQ files: a file listing paths, a
+        /// directory, a sourmash zip collection or a manifest CSV. Use `-`
+        /// to read signatures from stdin and write results to stdout as
+        /// JSON lines
         #[structopt(parse(from_os_str))]
         query_path: PathBuf,
 
-        /// Precomputed index or list of reference signatures
+        /// Precomputed index (binary or JSON) or, with --from-file, reference
+        /// signatures (path list, directory, zip collection or manifest CSV)
         #[structopt(parse(from_os_str))]
         siglist: PathBuf,
 
@@ -32,6 +61,10 @@ enum Cli {
         #[structopt(short = "s", long = "scaled", default_value = "1000")]
         scaled: usize,
 
+        /// Molecule type (DNA, protein, dayhoff or hp)
+        #[structopt(long = "moltype", default_value = "DNA", parse(try_from_str = parse_moltype))]
+        moltype: HashFunctions,
+
         /// threshold_bp
         #[structopt(short = "t", long = "threshold_bp", default_value = "50000")]
         threshold_bp: usize,
@@ -51,13 +84,148 @@ enum Cli {
         /// Preload reference signatures into memory
         #[structopt(long = "--preload")]
         preload: bool,
+
+        /// Memory-map a binary index instead of loading it into memory
+        #[structopt(long = "--mmap", conflicts_with = "from-file")]
+        mmap: bool,
+
+        /// Number of threads to use (0 uses one per CPU)
+        #[structopt(long = "--threads", default_value = "0")]
+        threads: usize,
+
+        /// Memory budget for reference signatures loaded during gather, in
+        /// bytes or with a K, M or G suffix. The least recently used
+        /// signatures are dropped when it is exceeded
+        #[structopt(long = "--max-memory", parse(try_from_str = parse_memory))]
+        max_memory: Option<usize>,
+
+        /// Only use references listed in a picklist, as file.csv:column:type
+        /// (type is one of name, ident, identprefix, md5 or md5prefix8)
+        #[structopt(long = "picklist")]
+        picklist: Option<String>,
+
+        /// Skip references listed in a picklist, as file.csv:column:type
+        #[structopt(long = "exclude-picklist")]
+        exclude_picklist: Option<String>,
+
+        /// Keep processing other queries when one fails, and list failed
+        /// queries in `failed_queries.csv` in the output directory
+        #[structopt(long = "--keep-going")]
+        keep_going: bool,
+
+        /// Exit with an error if any query failed in --keep-going mode
+        #[structopt(long = "--fail-on-error", requires = "keep-going")]
+        fail_on_error: bool,
+
+        /// Keep query abundances and report abundance-weighted results
+        #[structopt(long = "--track-abundance")]
+        track_abundance: bool,
+
+        /// Skip queries completed by a previous run with the same output
+        /// directory and parameters
+        #[structopt(long = "--resume")]
+        resume: bool,
+
+        /// Output format for each query results
+        #[structopt(
+            long = "output-format",
+            default_value = "plain",
+            possible_values = OutputFormat::VARIANTS,
+            case_insensitive = true
+        )]
+        output_format: OutputFormat,
+
+        /// How to name the results for each query: query filename (flat),
+        /// query path relative to the common directory of all queries
+        /// (mirror), query md5 or name, or one file for all queries
+        /// (combined)
+        #[structopt(
+            long = "output-layout",
+            default_value = "flat",
+            possible_values = OutputLayout::VARIANTS,
+            case_insensitive = true
+        )]
+        output_layout: OutputLayout,
     },
-    Index {
+    Search {
+        /// Query signatures or FASTA/FASTQ files: a file listing paths, a
+        /// directory, a sourmash zip collection or a manifest CSV
+        #[structopt(parse(from_os_str))]
+        query_path: PathBuf,
+
+        /// Precomputed index
+        #[structopt(parse(from_os_str))]
+        siglist: PathBuf,
+
+        /// ksize
+        #[structopt(short = "k", long = "ksize", default_value = "31")]
+        ksize: u8,
+
+        /// scaled
+        #[structopt(short = "s", long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        /// Molecule type (DNA, protein, dayhoff or hp)
+        #[structopt(long = "moltype", default_value = "DNA", parse(try_from_str = parse_moltype))]
+        moltype: HashFunctions,
+
+        /// Minimum similarity (or containment) to report a match
+        #[structopt(short = "t", long = "threshold", default_value = "0.08")]
+        threshold: f64,
+
+        /// Only use references listed in a picklist, as file.csv:column:type
+        /// (type is one of name, ident, identprefix, md5 or md5prefix8)
+        #[structopt(long = "picklist")]
+        picklist: Option<String>,
+
+        /// Skip references listed in a picklist, as file.csv:column:type
+        #[structopt(long = "exclude-picklist")]
+        exclude_picklist: Option<String>,
+
+        /// Score matches by containment of the query
+        #[structopt(long = "containment", conflicts_with = "jaccard")]
+        containment: bool,
+
+        /// Score matches by Jaccard similarity (default)
+        #[structopt(long = "jaccard")]
+        jaccard: bool,
+
         /// The path for output
+        #[structopt(parse(from_os_str), short = "o", long = "output")]
+        output: Option<PathBuf>,
+
+        /// Is the index a list of signatures?
+        #[structopt(long = "--from-file")]
+        from_file: bool,
+
+        /// Keep processing other queries when one fails, and list failed
+        /// queries in `failed_queries.csv` in the output directory
+        #[structopt(long = "--keep-going")]
+        keep_going: bool,
+
+        /// Exit with an error if any query failed in --keep-going mode
+        #[structopt(long = "--fail-on-error", requires = "keep-going")]
+        fail_on_error: bool,
+
+        /// How to name the outputs for each query: query filename (flat),
+        /// query path relative to the common directory of all queries
+        /// (mirror), query md5 or name
+        #[structopt(
+            long = "output-layout",
+            default_value = "flat",
+            possible_values = OutputLayout::VARIANTS,
+            case_insensitive = true
+        )]
+        output_layout: OutputLayout,
+    },
+    /// List every indexed dataset sharing hashes with each query
+    Prefetch {
+        /// Query signatures or FASTA/FASTQ files: a file listing paths, a
+        /// directory, a sourmash zip collection or a manifest CSV
         #[structopt(parse(from_os_str))]
-        output: PathBuf,
+        query_path: PathBuf,
 
-        /// List of reference signatures
+        /// Precomputed index or, with --from-file, reference signatures
         #[structopt(parse(from_os_str))]
         siglist: PathBuf,
 
@@ -68,156 +236,873 @@ enum Cli {
         /// scaled
         #[structopt(short = "s", long = "scaled", default_value = "1000")]
         scaled: usize,
+
+        /// Molecule type (DNA, protein, dayhoff or hp)
+        #[structopt(long = "moltype", default_value = "DNA", parse(try_from_str = parse_moltype))]
+        moltype: HashFunctions,
+
+        /// Minimum overlap (in bp, estimated from shared hashes) to report
+        /// a dataset
+        #[structopt(short = "t", long = "threshold_bp", default_value = "50000")]
+        threshold_bp: usize,
+
+        /// The directory for output
+        #[structopt(parse(from_os_str), short = "o", long = "output")]
+        output: Option<PathBuf>,
+
+        /// Is the index a list of signatures?
+        #[structopt(long = "--from-file")]
+        from_file: bool,
+
+        /// Keep processing other queries when one fails, and list failed
+        /// queries in `failed_queries.csv` in the output directory
+        #[structopt(long = "--keep-going")]
+        keep_going: bool,
+
+        /// Exit with an error if any query failed in --keep-going mode
+        #[structopt(long = "--fail-on-error", requires = "keep-going")]
+        fail_on_error: bool,
+
+        /// Only use references listed in a picklist, as file.csv:column:type
+        /// (type is one of name, ident, identprefix, md5 or md5prefix8)
+        #[structopt(long = "picklist")]
+        picklist: Option<String>,
+
+        /// Skip references listed in a picklist, as file.csv:column:type
+        #[structopt(long = "exclude-picklist")]
+        exclude_picklist: Option<String>,
+
+        /// Save the paths of all matched signatures to this file, one per
+        /// line, to use as a siglist for a smaller index
+        #[structopt(parse(from_os_str), long = "save-matches")]
+        save_matches: Option<PathBuf>,
+
+        /// How to name the outputs for each query: query filename (flat),
+        /// query path relative to the common directory of all queries
+        /// (mirror), query md5 or name
+        #[structopt(
+            long = "output-layout",
+            default_value = "flat",
+            possible_values = OutputLayout::VARIANTS,
+            case_insensitive = true
+        )]
+        output_layout: OutputLayout,
     },
+    /// Remove hashes shared with gather matches (or picklist matches) from
+    /// queries, saving the remaining sketches as new signatures
+    Subtract {
+        /// Query signatures or FASTA/FASTQ files: a file listing paths, a
+        /// directory, a sourmash zip collection or a manifest CSV
+        #[structopt(parse(from_os_str))]
+        query_path: PathBuf,
+
+        /// Precomputed index or, with --from-file, reference signatures
+        #[structopt(parse(from_os_str))]
+        siglist: PathBuf,
+
+        /// ksize
+        #[structopt(short = "k", long = "ksize", default_value = "31")]
+        ksize: u8,
+
+        /// scaled
+        #[structopt(short = "s", long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        /// Molecule type (DNA, protein, dayhoff or hp)
+        #[structopt(long = "moltype", default_value = "DNA", parse(try_from_str = parse_moltype))]
+        moltype: HashFunctions,
+
+        /// Minimum overlap (in bp, estimated from shared hashes) for gather
+        /// matches to be removed
+        #[structopt(short = "t", long = "threshold_bp", default_value = "50000")]
+        threshold_bp: usize,
+
+        /// The directory for output
+        #[structopt(parse(from_os_str), short = "o", long = "output")]
+        output: Option<PathBuf>,
+
+        /// Is the index a list of signatures?
+        #[structopt(long = "--from-file")]
+        from_file: bool,
+
+        /// Keep processing other queries when one fails, and list failed
+        /// queries in `failed_queries.csv` in the output directory
+        #[structopt(long = "--keep-going")]
+        keep_going: bool,
+
+        /// Exit with an error if any query failed in --keep-going mode
+        #[structopt(long = "--fail-on-error", requires = "keep-going")]
+        fail_on_error: bool,
+
+        /// Remove all overlapping references listed in a picklist, as
+        /// file.csv:column:type, instead of gather matches
+        #[structopt(long = "picklist")]
+        picklist: Option<String>,
+
+        /// Remove all overlapping references except the ones listed in a
+        /// picklist, as file.csv:column:type
+        #[structopt(long = "exclude-picklist")]
+        exclude_picklist: Option<String>,
+
+        /// Keep query abundances in the subtracted signatures
+        #[structopt(long = "--track-abundance")]
+        track_abundance: bool,
+
+        /// Also save the reads from FASTA/FASTQ queries, dropping reads with
+        /// mostly removed hashes. Only hashes at the index scaled are
+        /// compared, so short reads need a small scaled
+        #[structopt(long = "--filter-reads")]
+        filter_reads: bool,
+
+        /// Drop reads with more than this fraction of their hashes removed
+        #[structopt(long = "max-removed-fraction", default_value = "0.5")]
+        max_removed_fraction: f64,
+
+        /// How to name the outputs for each query: query filename (flat),
+        /// query path relative to the common directory of all queries
+        /// (mirror), query md5 or name
+        #[structopt(
+            long = "output-layout",
+            default_value = "flat",
+            possible_values = OutputLayout::VARIANTS,
+            case_insensitive = true
+        )]
+        output_layout: OutputLayout,
+    },
+    /// Build signatures from FASTA/FASTQ files
+    Sketch {
+        /// List of FASTA/FASTQ files (optionally compressed)
+        #[structopt(parse(from_os_str))]
+        inputs: PathBuf,
+
+        /// ksize
+        #[structopt(short = "k", long = "ksize", default_value = "31")]
+        ksize: u8,
+
+        /// scaled
+        #[structopt(short = "s", long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        /// Record how many times each hash was seen
+        #[structopt(long = "--track-abundance")]
+        track_abundance: bool,
+
+        /// The directory for output signatures
+        #[structopt(parse(from_os_str), short = "o", long = "output", default_value = ".")]
+        output: PathBuf,
+
+        /// How to name the output signatures: input filename (flat), input
+        /// path relative to the common directory of all inputs (mirror),
+        /// sketch md5 or name
+        #[structopt(
+            long = "output-layout",
+            default_value = "flat",
+            possible_values = OutputLayout::VARIANTS,
+            case_insensitive = true
+        )]
+        output_layout: OutputLayout,
+    },
+    /// Summarize gather results by taxonomic rank
+    Tax {
+        /// List of gather results (from `gather --output-format csv`)
+        #[structopt(parse(from_os_str))]
+        gather_results: PathBuf,
+
+        /// Lineages CSV, with accessions in the first column and one column
+        /// per rank (GTDB or NCBI style)
+        #[structopt(parse(from_os_str), short = "t", long = "taxonomy")]
+        taxonomy: PathBuf,
+
+        /// Output format for each query summary
+        #[structopt(
+            long = "output-format",
+            default_value = "csv",
+            possible_values = TaxFormat::VARIANTS,
+            case_insensitive = true
+        )]
+        output_format: TaxFormat,
+
+        /// The directory for output summaries
+        #[structopt(parse(from_os_str), short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Merge indices built with the same ksize and scaled
+    Merge {
+        /// The path for output
+        #[structopt(parse(from_os_str), short = "o", long = "output")]
+        output: PathBuf,
+
+        /// Indices to merge
+        #[structopt(parse(from_os_str), required = true, min_values = 2)]
+        indices: Vec<PathBuf>,
+
+        /// Format for the saved index
+        #[structopt(
+            long = "index-format",
+            default_value = "binary",
+            possible_values = IndexFormat::VARIANTS,
+            case_insensitive = true
+        )]
+        index_format: IndexFormat,
+    },
+    #[structopt(setting = structopt::clap::AppSettings::SubcommandsNegateReqs)]
+    Index {
+        /// The path for output
+        #[structopt(parse(from_os_str), required = true)]
+        output: Option<PathBuf>,
+
+        /// Reference signatures: a file listing paths, a directory, a
+        /// sourmash zip collection or a manifest CSV
+        #[structopt(parse(from_os_str), required = true)]
+        siglist: Option<PathBuf>,
+
+        /// ksizes, comma-separated. Several ksizes or moltypes are saved
+        /// in a container directory, with one index for each
+        #[structopt(
+            short = "k",
+            long = "ksize",
+            default_value = "31",
+            use_delimiter = true
+        )]
+        ksize: Vec<u8>,
+
+        /// scaled
+        #[structopt(short = "s", long = "scaled", default_value = "1000")]
+        scaled: usize,
+
+        /// Molecule types (DNA, protein, dayhoff or hp), comma-separated
+        #[structopt(
+            long = "moltype",
+            default_value = "DNA",
+            use_delimiter = true,
+            parse(try_from_str = parse_moltype)
+        )]
+        moltype: Vec<HashFunctions>,
+
+        /// Format for the saved index: gzipped JSON (readable by the sourmash
+        /// greyhound index) or the faster binary format
+        #[structopt(
+            long = "index-format",
+            default_value = "json",
+            possible_values = IndexFormat::VARIANTS,
+            case_insensitive = true
+        )]
+        index_format: IndexFormat,
+
+        /// Add signatures to an existing index (or container) at the output
+        /// path. The existing index format is kept.
+        #[structopt(long = "--append")]
+        append: bool,
+
+        #[structopt(subcommand)]
+        cmd: Option<IndexCmd>,
+    },
+}
+
+/// Operations on existing indices
+#[derive(StructOpt, Debug)]
+enum IndexCmd {
+    /// Remove datasets from an index
+    Remove {
+        /// Index to remove datasets from
+        #[structopt(parse(from_os_str))]
+        index_path: PathBuf,
+
+        /// File with names, signature paths or md5s to remove, one per line
+        #[structopt(parse(from_os_str))]
+        to_remove: PathBuf,
+
+        /// The path for the updated index (defaults to updating in place)
+        #[structopt(parse(from_os_str), short = "o", long = "output")]
+        output: Option<PathBuf>,
+    },
+    /// Report the template, size and sharing of hashes in an index
+    #[structopt(alias = "inspect")]
+    Stats {
+        /// Index to inspect
+        #[structopt(parse(from_os_str))]
+        index_path: PathBuf,
+
+        /// How many of the most shared hashes to report
+        #[structopt(long = "top", default_value = "10")]
+        top: usize,
+
+        /// Output as JSON
+        #[structopt(long = "json")]
+        json: bool,
+    },
+}
+
+fn read_lines<P: AsRef<Path>>(file: P) -> Result<Vec<String>, Error> {
+    let lines = BufReader::new(File::open(file)?);
+    let mut result = vec![];
+    for line in lines.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            result.push(line);
+        }
+    }
+    Ok(result)
 }
 
-fn read_paths<P: AsRef<Path>>(paths_file: P) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
-    let paths = BufReader::new(File::open(paths_file)?);
-    Ok(paths
-        .lines()
-        .map(|line| {
-            let mut path = PathBuf::new();
-            path.push(line.unwrap());
-            path
-        })
+fn read_paths<P: AsRef<Path>>(paths_file: P) -> Result<Vec<PathBuf>, Error> {
+    Ok(read_lines(paths_file)?
+        .into_iter()
+        .map(PathBuf::from)
         .collect())
 }
 
-fn build_template(ksize: u8, scaled: usize) -> Sketch {
-    let max_hash = max_hash_for_scaled(scaled as u64);
+fn parse_moltype(moltype: &str) -> Result<HashFunctions, String> {
+    match moltype.to_lowercase().as_str() {
+        "dna" => Ok(HashFunctions::murmur64_DNA),
+        "protein" => Ok(HashFunctions::murmur64_protein),
+        "dayhoff" => Ok(HashFunctions::murmur64_dayhoff),
+        "hp" => Ok(HashFunctions::murmur64_hp),
+        _ => Err(format!("unknown molecule type: {}", moltype)),
+    }
+}
+
+fn parse_memory(memory: &str) -> Result<usize, String> {
+    let memory = memory.trim();
+    let (digits, unit) = match memory.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => memory.split_at(pos),
+        None => (memory, ""),
+    };
+    let multiplier: usize = match unit.trim().to_lowercase().trim_end_matches('b') {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        _ => return Err(format!("unknown memory unit: {}", unit)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|value| value.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory size: {}", memory))
+}
+
+/// Build a template from the CLI parameters. As in sourmash, `ksize` counts
+/// amino acids for protein, dayhoff and hp, and their sketches store the
+/// k-mer size in nucleotides (three times larger).
+fn build_template(
+    ksize: u8,
+    scaled: usize,
+    moltype: HashFunctions,
+    track_abundance: bool,
+) -> Sketch {
+    let ksize = if moltype == HashFunctions::murmur64_DNA {
+        ksize as u32
+    } else {
+        ksize as u32 * 3
+    };
+    minhash_template(ksize, scaled as u64, moltype, track_abundance)
+}
+
+/// Build a template with `ksize` as stored in sketches.
+fn minhash_template(
+    ksize: u32,
+    scaled: u64,
+    moltype: HashFunctions,
+    track_abundance: bool,
+) -> Sketch {
+    let max_hash = max_hash_for_scaled(scaled);
+    let abunds = if track_abundance { Some(vec![]) } else { None };
     let template_mh = KmerMinHash::builder()
         .num(0u32)
-        .ksize(ksize as u32)
+        .ksize(ksize)
+        .hash_function(moltype)
         .max_hash(max_hash)
+        .abunds(abunds)
         .build();
     Sketch::MinHash(template_mh)
 }
 
-fn index<P: AsRef<Path>>(
-    siglist: P,
-    template: Sketch,
-    output: P,
-) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Loading siglist");
-    let index_sigs = read_paths(siglist)?;
-    info!("Loaded {} sig paths in siglist", index_sigs.len());
+fn load_picklists(
+    picklist: Option<String>,
+    exclude_picklist: Option<String>,
+) -> Result<Vec<Picklist>, Error> {
+    let mut picklists = vec![];
+    if let Some(spec) = picklist {
+        picklists.push(Picklist::from_spec(&spec, false)?);
+    }
+    if let Some(spec) = exclude_picklist {
+        picklists.push(Picklist::from_spec(&spec, true)?);
+    }
+    Ok(picklists)
+}
 
-    let revindex = RevIndex::new(&index_sigs, &template, 0, None, false);
+/// Find which datasets in `revindex` pass the picklists, if there are any.
+fn pick_datasets(
+    revindex: &mut RevIndex,
+    picklists: &[Picklist],
+) -> Result<Option<Vec<bool>>, Error> {
+    if picklists.is_empty() {
+        return Ok(None);
+    }
 
-    info!("Saving index");
-    let wtr = niffler::to_path(
-        output,
-        niffler::compression::Format::Gzip,
-        niffler::compression::Level::One,
-    )?;
-    serde_json::to_writer(wtr, &revindex)?;
+    let picked = revindex.pick_datasets(picklists)?;
+    for picklist in picklists {
+        info!("Using {}", picklist);
+    }
+    info!(
+        "{} of {} datasets selected by picklists",
+        picked.iter().filter(|p| **p).count(),
+        picked.len()
+    );
+    Ok(Some(picked))
+}
 
-    Ok(())
+/// How to open the index for a command, see `open_index`.
+#[derive(Debug, Default)]
+struct IndexOptions<'a> {
+    /// The index path is a file listing signatures to build the index from
+    from_file: bool,
+    /// Memory-map a binary index instead of loading it into memory
+    mmap: bool,
+    /// Only keep hashes present in these queries
+    queries: Option<&'a [KmerMinHash]>,
+    /// Minimum hashes shared with a query for keeping a dataset, when
+    /// building the index from signatures
+    threshold: usize,
+    /// Keep reference signatures in memory, when building the index from
+    /// signatures
+    keep_sigs: bool,
+    picklists: &'a [Picklist],
 }
 
-fn gather<P: AsRef<Path>>(
-    queries_file: P,
-    siglist: P,
-    template: Sketch,
-    threshold_bp: usize,
-    output: Option<P>,
+/// Open the index at `index_path` (as returned by `resolve_index`), and
+/// find which datasets pass the picklists.
+fn open_index(
+    index_path: &Path,
+    template: &Sketch,
+    opts: &IndexOptions,
+) -> Result<(RevIndex, Option<Vec<bool>>), Error> {
+    let mut revindex = if opts.from_file {
+        info!("Loading siglist");
+        let search_sigs = list_signatures(index_path, Some(template))?;
+        info!("Loaded {} sig paths in siglist", search_sigs.len());
+
+        RevIndex::new(
+            &search_sigs,
+            template,
+            opts.threshold,
+            opts.queries,
+            opts.keep_sigs,
+        )?
+    } else if opts.mmap {
+        RevIndex::load_mmap(index_path)?
+    } else {
+        RevIndex::load(index_path, opts.queries)?
+    };
+
+    let picked = pick_datasets(&mut revindex, opts.picklists)?;
+    Ok((revindex, picked))
+}
+
+/// How to load queries and the index for commands saving one output per
+/// query, see `QueryBatch::load`.
+#[derive(Debug)]
+struct BatchOptions<'a> {
+    /// Command name, for errors
+    command: &'a str,
+    /// The index path is a file listing signatures to build the index from
     from_file: bool,
-    lazy: bool,
-    preload: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    info!("Loading queries");
-
-    let queries_path = read_paths(queries_file)?;
-
-    let mut queries = vec![];
-    let mut threshold = usize::max_value();
-    if !lazy || from_file {
-        for query_path in &queries_path {
-            let query_sig = Signature::from_path(query_path)?;
-            let mut query = None;
-            for sig in &query_sig {
-                if let Some(sketch) = sig.select_sketch(&template) {
-                    if let Sketch::MinHash(mh) = sketch {
-                        query = Some(mh.clone());
-                        // TODO: deal with mh.size() == 0
-                        let t = threshold_bp / (cmp::max(mh.size(), 1) * mh.scaled() as usize);
-                        threshold = cmp::min(threshold, t);
+    /// Minimum overlap (in bp) with a query for keeping a dataset, when
+    /// building the index from signatures
+    threshold_bp: usize,
+    keep_going: bool,
+    fail_on_error: bool,
+    picklists: &'a [Picklist],
+    output_layout: OutputLayout,
+}
+
+/// Queries loaded together with the index they are compared against, and
+/// the output paths claimed for them.
+struct QueryBatch {
+    /// Template used for queries, from the index if it is prebuilt
+    template: Sketch,
+    /// `threshold_bp` in hashes at the template scaled
+    threshold: usize,
+    outdir: PathBuf,
+    queries_path: Vec<PathBuf>,
+    /// Position in `queries_path` of each loaded query
+    query_ids: Vec<usize>,
+    names: Vec<String>,
+    queries: Vec<KmerMinHash>,
+    out_stems: Vec<PathBuf>,
+    revindex: RevIndex,
+    picked: Option<Vec<bool>>,
+    keep_going: bool,
+    fail_on_error: bool,
+}
+
+/// A query in a `QueryBatch`, with the output path claimed for it (without
+/// any extension).
+struct BatchQuery<'a> {
+    path: &'a Path,
+    name: &'a str,
+    minhash: &'a KmerMinHash,
+    out_stem: &'a Path,
+}
+
+impl QueryBatch {
+    /// Load the queries in `queries_file` and the index at `index_path`, and
+    /// claim an output path in `output` (`outputs` by default) for each
+    /// query, failing before anything is written if two queries would
+    /// overwrite each other.
+    ///
+    /// With `opts.keep_going`, queries failing to load are returned with
+    /// their position in `queries_path`, to be reported by `run`.
+    fn load(
+        queries_file: &Path,
+        index_path: &Path,
+        template: Sketch,
+        output: Option<&Path>,
+        opts: &BatchOptions,
+    ) -> Result<(QueryBatch, Vec<(usize, Error)>), Error> {
+        opts.output_layout.require_per_query(opts.command)?;
+
+        // Prebuilt indices define the template used for queries
+        let (index_path, template) = if opts.from_file {
+            (index_path.into(), template)
+        } else {
+            resolve_index(index_path, &template)?
+        };
+        let scaled = match &template {
+            Sketch::MinHash(mh) => mh.scaled() as usize,
+            _ => 1,
+        };
+        let threshold = opts.threshold_bp / scaled;
+
+        info!("Loading queries");
+        let queries_path = list_queries(queries_file, Some(&template))?;
+        let loaded: Vec<_> = queries_path
+            .par_iter()
+            .map(|query_path| load_named_query(query_path, &template))
+            .collect();
+
+        let mut failures = vec![];
+        let mut query_ids = vec![];
+        let mut names = vec![];
+        let mut queries = vec![];
+        for (i, query) in loaded.into_iter().enumerate() {
+            match query {
+                Ok((name, query)) => {
+                    query_ids.push(i);
+                    names.push(name);
+                    queries.push(query);
+                }
+                Err(e) => {
+                    let e = query_error(&queries_path[i], e);
+                    if !opts.keep_going {
+                        return Err(e);
                     }
+                    warn!("{}", e);
+                    failures.push((i, e));
                 }
             }
-            if let Some(q) = query {
-                queries.push(q);
-            } else {
-                todo!("throw error, some sigs were not valid")
-            };
         }
+        info!("Loaded {} query signatures", queries.len());
+
+        let index_opts = IndexOptions {
+            from_file: opts.from_file,
+            queries: Some(&queries[..]),
+            threshold,
+            picklists: opts.picklists,
+            ..Default::default()
+        };
+        let (revindex, picked) = open_index(&index_path, &template, &index_opts)?;
+
+        let outdir: PathBuf = output.unwrap_or(Path::new("outputs")).into();
+        std::fs::create_dir_all(&outdir)?;
+
+        let base = common_base(&queries_path);
+        let outputs = OutputNames::default();
+        let out_stems = query_ids
+            .iter()
+            .zip(queries.iter().zip(&names))
+            .map(|(&i, (query, name))| {
+                let md5 = query.md5sum();
+                outputs.claim_stem(
+                    opts.output_layout,
+                    &outdir,
+                    &base,
+                    &queries_path[i],
+                    Some((name.as_str(), md5.as_str())),
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let batch = QueryBatch {
+            template,
+            threshold,
+            outdir,
+            queries_path,
+            query_ids,
+            names,
+            queries,
+            out_stems,
+            revindex,
+            picked,
+            keep_going: opts.keep_going,
+            fail_on_error: opts.fail_on_error,
+        };
+        Ok((batch, failures))
     }
 
-    info!("Loaded {} query signatures", queries_path.len());
+    /// Run `f` for each query in parallel, returning the results of queries
+    /// that succeeded.
+    ///
+    /// With --keep-going, failed queries (and the load `failures`) are listed
+    /// in `failed_queries.csv` in the output directory instead of stopping at
+    /// the first one.
+    fn run<T, F>(&self, mut failures: Vec<(usize, Error)>, f: F) -> Result<Vec<T>, Error>
+    where
+        T: Send,
+        F: Fn(BatchQuery) -> Result<T, Error> + Sync,
+    {
+        let run_query = |j: usize| {
+            let query_path = &self.queries_path[self.query_ids[j]];
+            f(BatchQuery {
+                path: query_path,
+                name: &self.names[j],
+                minhash: &self.queries[j],
+                out_stem: &self.out_stems[j],
+            })
+            .map_err(|e| query_error(query_path, e))
+        };
 
-    // Step 1: filter and prepare a reduced RevIndex for all queries
-    let revindex = if from_file {
-        info!("Loading siglist");
-        let search_sigs = read_paths(siglist)?;
-        info!("Loaded {} sig paths in siglist", search_sigs.len());
+        let pending = (0..self.queries.len()).into_par_iter();
+        if !self.keep_going {
+            return pending.map(run_query).collect();
+        }
+
+        let results: Vec<_> = pending.map(run_query).collect();
+        let mut completed = vec![];
+        for (j, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => completed.push(value),
+                Err(e) => {
+                    warn!("{}", e);
+                    failures.push((self.query_ids[j], e));
+                }
+            }
+        }
 
-        RevIndex::new(&search_sigs, &template, threshold, Some(&queries), preload)
+        let path = self.outdir.join("failed_queries.csv");
+        write_failures(&path, &self.queries_path, &mut failures)?;
+        if !failures.is_empty() {
+            warn!(
+                "{} of {} queries failed, see {:?}",
+                failures.len(),
+                self.queries_path.len(),
+                path
+            );
+            if self.fail_on_error {
+                return Err(Error::FailedQueries(failures.len()));
+            }
+        }
+        Ok(completed)
+    }
+}
+
+/// Load a query sketch compatible with `template`. FASTA/FASTQ files are
+/// sketched with the template parameters, and sketches with a smaller
+/// scaled are downsampled to the template scaled.
+///
+/// Abundances are kept only if the template tracks abundance.
+fn load_query<P: AsRef<Path>>(path: P, template: &Sketch) -> Result<KmerMinHash, Error> {
+    load_named_query(path, template).map(|(_, query)| query)
+}
+
+/// Load a query like `load_query`, together with its signature name.
+fn load_named_query<P: AsRef<Path>>(
+    path: P,
+    template: &Sketch,
+) -> Result<(String, KmerMinHash), Error> {
+    let query_sig = if path.as_ref().is_file() && is_sequence_file(&path)? {
+        vec![sketch::sketch_for_template(&path, template)?]
     } else {
-        if lazy {
-            RevIndex::load(siglist, None)
-        } else {
-            RevIndex::load(siglist, Some(&queries))
-        }?
+        load_signatures(&path)?
     };
+    select_query(&query_sig, template, path.as_ref())
+}
 
-    let outdir: PathBuf = if let Some(p) = output {
-        p.as_ref().into()
-    } else {
-        let mut path = PathBuf::new();
-        path.push("outputs");
-        path
+/// Select the query sketch compatible with `template` from signatures read
+/// from `source`, like `load_query`. The name of the selected signature is
+/// returned too.
+fn select_query(
+    query_sig: &[Signature],
+    template: &Sketch,
+    source: &Path,
+) -> Result<(String, KmerMinHash), Error> {
+    let (name, mut query) = match select_minhash(query_sig, template)? {
+        Some((sig, mh)) => (sig.name(), mh.into_owned()),
+        None => return Err(Error::NoCompatibleSketch(source.into())),
     };
-    std::fs::create_dir_all(&outdir)?;
-
-    // Step 2: Gather using the RevIndex and a specific Counter for each query
-    queries_path.par_iter().enumerate().for_each(|(i, query)| {
-        let query = if lazy {
-            let query_sig = Signature::from_path(query).unwrap();
-            let mut query = None;
-            for sig in &query_sig {
-                if let Some(sketch) = sig.select_sketch(&template) {
-                    if let Sketch::MinHash(mh) = sketch {
-                        if mh.size() == 0 {
-                            return;
-                        }
-                        query = Some(mh.clone());
-                    }
-                }
+
+    let track_abundance = match template {
+        Sketch::MinHash(mh) => mh.track_abundance(),
+        _ => false,
+    };
+    if !track_abundance {
+        query.disable_abundance();
+    } else if !query.track_abundance() {
+        warn!(
+            "Query {:?} has no abundance, reporting unweighted results",
+            source
+        );
+    }
+    Ok((name, query))
+}
+
+/// Find the index to use for `template`, and the template to load queries
+/// with.
+///
+/// If `index_path` is a container with several templates, the index with
+/// the same ksize and molecule type is selected (preferring one with the same
+/// scaled too). The returned template uses the index scaled, so queries
+/// with a smaller scaled are downsampled to it.
+fn resolve_index(index_path: &Path, template: &Sketch) -> Result<(PathBuf, Sketch), Error> {
+    let wanted = TemplateEntry::from_template(template, "")?;
+    let track_abundance = match template {
+        Sketch::MinHash(mh) => mh.track_abundance(),
+        _ => false,
+    };
+
+    let index_path = if MultiIndex::is_multi(index_path) {
+        let multi = MultiIndex::open(index_path)?;
+        let ksize = Some(wanted.ksize());
+        let moltype = Some(wanted.moltype().as_str());
+        let entry = match multi.select(ksize, moltype, Some(wanted.scaled())) {
+            Ok(entry) => entry,
+            Err(greyhound_core::Error::NoMatchingTemplate(_)) => {
+                multi.select(ksize, moltype, None)?
             }
-            query.unwrap()
-        } else {
-            queries[i].clone()
+            Err(e) => return Err(e.into()),
         };
+        info!("Using index for {} in {:?}", entry, index_path);
+        multi.index_path(entry)
+    } else {
+        index_path.into()
+    };
 
-        info!("Build counter for query");
-        let counter = revindex.counter_for_query(&query);
-        let threshold = threshold_bp / (query.size() * query.scaled() as usize);
+    let index_template = RevIndex::read_template(&index_path)?;
+    let found = TemplateEntry::from_template(&index_template, "")?;
+    if found.ksize() != wanted.ksize() || found.moltype() != wanted.moltype() {
+        return Err(greyhound_core::Error::IncompatibleTemplate(format!(
+            "index uses {}, not {}",
+            found, wanted
+        ))
+        .into());
+    }
+    if found.scaled() != wanted.scaled() {
+        info!(
+            "Index uses scaled={}, queries will be downsampled to it",
+            found.scaled()
+        );
+    }
+
+    let moltype =
+        parse_moltype(found.moltype()).map_err(greyhound_core::Error::IncompatibleTemplate)?;
+    let template = minhash_template(found.ksize(), found.scaled(), moltype, track_abundance);
+    Ok((index_path, template))
+}
 
-        info!("Starting gather");
-        let matches = revindex.gather(counter, threshold, &query).unwrap();
+fn index<P: AsRef<Path>>(
+    siglist: P,
+    templates: &[Sketch],
+    output: P,
+    index_format: IndexFormat,
+    append: bool,
+) -> Result<(), Error> {
+    if templates.len() > 1 || MultiIndex::is_multi(&output) {
+        let mut multi = MultiIndex::create(&output)?;
+        for template in templates {
+            let entry = TemplateEntry::from_template(template, "")?;
 
-        info!("Saving {} matches", matches.len());
-        let mut path = outdir.clone();
-        path.push(queries_path[i].file_name().unwrap());
+            // Manifests allow listing only signatures compatible with each template
+            info!("Loading siglist for {}", entry);
+            let index_sigs = list_signatures(&siglist, Some(template))?;
 
-        let mut out = BufWriter::new(File::create(path).unwrap());
-        for m in matches {
-            writeln!(out, "{}", m.filename().as_str()).unwrap();
+            let (revindex, index_format) = match multi.find(template) {
+                Some(existing) if append => {
+                    let path = multi.index_path(existing);
+                    info!("Loading existing index for {}", entry);
+                    let mut revindex = RevIndex::load(&path, None)?;
+                    append_sigs(&mut revindex, &index_sigs)?;
+                    (revindex, IndexFormat::detect(&path)?)
+                }
+                _ => {
+                    info!("Building index for {}", entry);
+                    let revindex = RevIndex::new(&index_sigs, template, 0, None, false)?;
+                    check_compatible(&revindex, siglist.as_ref(), &entry)?;
+                    (revindex, index_format)
+                }
+            };
+
+            info!("Saving index for {}", entry);
+            multi.insert(&revindex, index_format)?;
         }
-        info!("Finishing query {:?}", queries_path[i]);
-    });
+        return Ok(());
+    }
+
+    let template = &templates[0];
+    info!("Loading siglist");
+    let index_sigs = list_signatures(&siglist, Some(template))?;
+    info!("Loaded {} sig paths in siglist", index_sigs.len());
+
+    if append && output.as_ref().exists() {
+        let index_format = IndexFormat::detect(&output)?;
+
+        info!("Loading existing index");
+        let mut revindex = RevIndex::load(&output, None)?;
+        revindex.check_template(template)?;
+        append_sigs(&mut revindex, &index_sigs)?;
+
+        info!("Saving index");
+        revindex.save(output, index_format)?;
+    } else {
+        let revindex = RevIndex::new(&index_sigs, template, 0, None, false)?;
+        check_compatible(
+            &revindex,
+            siglist.as_ref(),
+            &TemplateEntry::from_template(template, "")?,
+        )?;
+
+        info!("Saving index");
+        revindex.save(output, index_format)?;
+    }
 
-    info!("Finished");
+    Ok(())
+}
+
+/// Fail if no signature had a sketch for the index template, since such
+/// an index can never match anything.
+fn check_compatible(
+    revindex: &RevIndex,
+    siglist: &Path,
+    entry: &TemplateEntry,
+) -> Result<(), Error> {
+    if revindex.n_compatible() == 0 {
+        return Err(greyhound_core::Error::NoCompatibleSketch(format!(
+            "{:?} for {}",
+            siglist, entry
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+fn append_sigs(revindex: &mut RevIndex, index_sigs: &[PathBuf]) -> Result<(), Error> {
+    let added = revindex.append(index_sigs)?;
+    info!(
+        "Added {} new datasets, skipped {}",
+        added,
+        index_sigs.len() - added
+    );
     Ok(())
 }
 
@@ -230,34 +1115,202 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             siglist,
             ksize,
             scaled,
+            moltype,
             threshold_bp,
             output,
             from_file,
             lazy,
             preload,
+            mmap,
+            threads,
+            max_memory,
+            keep_going,
+            fail_on_error,
+            picklist,
+            exclude_picklist,
+            track_abundance,
+            resume,
+            output_format,
+            output_layout,
         } => {
-            let template = build_template(ksize, scaled);
+            if threads > 0 {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .build_global()?;
+            }
 
-            gather(
-                query_path,
-                siglist,
-                template,
+            let template = build_template(ksize, scaled, moltype, track_abundance);
+            let opts = GatherOptions {
+                picklists: load_picklists(picklist, exclude_picklist)?,
                 threshold_bp,
-                output,
                 from_file,
                 lazy,
                 preload,
-            )?
+                mmap,
+                max_memory,
+                output_format,
+                output_layout,
+                keep_going,
+                fail_on_error,
+                resume,
+            };
+
+            gather::gather(query_path, siglist, template, output, &opts)?
         }
+        Cli::Search {
+            query_path,
+            siglist,
+            ksize,
+            scaled,
+            moltype,
+            threshold,
+            containment,
+            jaccard: _,
+            output,
+            from_file,
+            keep_going,
+            fail_on_error,
+            picklist,
+            exclude_picklist,
+            output_layout,
+        } => {
+            let template = build_template(ksize, scaled, moltype, false);
+            let opts = SearchOptions {
+                picklists: load_picklists(picklist, exclude_picklist)?,
+                threshold,
+                containment,
+                from_file,
+                keep_going,
+                fail_on_error,
+                output_layout,
+            };
+
+            search::search(query_path, siglist, template, output, &opts)?
+        }
+        Cli::Prefetch {
+            query_path,
+            siglist,
+            ksize,
+            scaled,
+            moltype,
+            threshold_bp,
+            output,
+            from_file,
+            keep_going,
+            fail_on_error,
+            picklist,
+            exclude_picklist,
+            save_matches,
+            output_layout,
+        } => {
+            let template = build_template(ksize, scaled, moltype, false);
+            let opts = PrefetchOptions {
+                threshold_bp,
+                from_file,
+                keep_going,
+                fail_on_error,
+                picklists: load_picklists(picklist, exclude_picklist)?,
+                save_matches,
+                output_layout,
+            };
+
+            prefetch::prefetch(query_path, siglist, template, output, &opts)?
+        }
+        Cli::Subtract {
+            query_path,
+            siglist,
+            ksize,
+            scaled,
+            moltype,
+            threshold_bp,
+            output,
+            from_file,
+            keep_going,
+            fail_on_error,
+            picklist,
+            exclude_picklist,
+            track_abundance,
+            filter_reads,
+            max_removed_fraction,
+            output_layout,
+        } => {
+            let template = build_template(ksize, scaled, moltype, track_abundance);
+            let opts = SubtractOptions {
+                threshold_bp,
+                from_file,
+                keep_going,
+                fail_on_error,
+                picklists: load_picklists(picklist, exclude_picklist)?,
+                filter_reads,
+                max_removed_fraction,
+                output_layout,
+            };
+
+            subtract::subtract(query_path, siglist, template, output, &opts)?
+        }
+        Cli::Sketch {
+            inputs,
+            ksize,
+            scaled,
+            track_abundance,
+            output,
+            output_layout,
+        } => sketch::sketch(
+            inputs,
+            ksize,
+            scaled,
+            track_abundance,
+            output,
+            output_layout,
+        )?,
+        Cli::Tax {
+            gather_results,
+            taxonomy,
+            output_format,
+            output,
+        } => tax::summarize(gather_results, taxonomy, output_format, output)?,
+        Cli::Merge {
+            output,
+            indices,
+            index_format,
+        } => manage::merge(&indices, output, index_format)?,
+        Cli::Index { cmd: Some(cmd), .. } => match cmd {
+            IndexCmd::Remove {
+                index_path,
+                to_remove,
+                output,
+            } => manage::remove(index_path, to_remove, output)?,
+            IndexCmd::Stats {
+                index_path,
+                top,
+                json,
+            } => manage::stats(index_path, top, json)?,
+        },
         Cli::Index {
             output,
             siglist,
             ksize,
             scaled,
+            moltype,
+            index_format,
+            append,
+            cmd: None,
         } => {
-            let template = build_template(ksize, scaled);
+            let mut templates = vec![];
+            for moltype in moltype {
+                for k in &ksize {
+                    templates.push(build_template(*k, scaled, moltype, false));
+                }
+            }
 
-            index(siglist, template, output)?
+            // Both are required by the CLI parser if there is no subcommand
+            index(
+                siglist.unwrap(),
+                &templates,
+                output.unwrap(),
+                index_format,
+                append,
+            )?
         }
     };
 
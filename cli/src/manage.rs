@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use greyhound_core::{IndexFormat, IndexStats, RevIndex};
+use log::{info, warn};
+
+use crate::error::Error;
+use crate::read_lines;
+
+/// Remove datasets matching names, signature paths or md5s listed in
+/// `keys_file` from an index.
+pub fn remove<P: AsRef<Path>>(index_path: P, keys_file: P, output: Option<P>) -> Result<(), Error> {
+    let keys = read_lines(keys_file)?;
+
+    let index_format = IndexFormat::detect(&index_path)?;
+    info!("Loading index");
+    let mut revindex = RevIndex::load(&index_path, None)?;
+    revindex.load_dataset_info()?;
+
+    let mut to_remove = HashSet::new();
+    for key in &keys {
+        let found = revindex.find_datasets(key);
+        if found.is_empty() {
+            warn!("No dataset matching {}", key);
+        }
+        to_remove.extend(found);
+    }
+
+    let orphaned = revindex.remove(&to_remove)?;
+    info!(
+        "Removed {} datasets, {} hashes are not present in any remaining dataset",
+        to_remove.len(),
+        orphaned
+    );
+
+    let output: PathBuf = match output {
+        Some(p) => p.as_ref().into(),
+        None => index_path.as_ref().into(),
+    };
+    info!("Saving index");
+    revindex.save(output, index_format)?;
+
+    Ok(())
+}
+
+/// Merge several indices built with the same template into one.
+pub fn merge<P: AsRef<Path>>(
+    indices: &[P],
+    output: P,
+    index_format: IndexFormat,
+) -> Result<(), Error> {
+    let (first, rest) = indices.split_first().ok_or_else(|| {
+        Error::InvalidArguments("at least one index is required for merging".into())
+    })?;
+
+    info!("Loading index {:?}", first.as_ref());
+    let mut revindex = RevIndex::load(first, None)?;
+
+    for path in rest {
+        info!("Loading index {:?}", path.as_ref());
+        let other = RevIndex::load(path, None)?;
+        revindex.merge(other).map_err(|e| Error::Merge {
+            path: path.as_ref().into(),
+            source: e,
+        })?;
+    }
+
+    info!("Saving index");
+    revindex.save(output, index_format)?;
+
+    Ok(())
+}
+
+/// Report what is inside an index, as text or JSON.
+pub fn stats<P: AsRef<Path>>(index_path: P, top: usize, json: bool) -> Result<(), Error> {
+    // Binary indices can be summarized without loading postings into memory
+    let revindex = match IndexFormat::detect(&index_path)? {
+        IndexFormat::Binary => RevIndex::load_mmap(&index_path)?,
+        IndexFormat::Json => RevIndex::load(&index_path, None)?,
+    };
+    let stats = revindex.stats(top)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        serde_json::to_writer_pretty(&mut out, &stats)?;
+        writeln!(out)?;
+    } else {
+        write_stats(&mut out, &stats)?;
+    }
+
+    Ok(())
+}
+
+fn write_stats<W: Write>(out: &mut W, stats: &IndexStats) -> io::Result<()> {
+    let unknown = || "?".to_string();
+    writeln!(
+        out,
+        "template: k={} scaled={} moltype={}",
+        stats.ksize.map_or_else(unknown, |k| k.to_string()),
+        stats.scaled.map_or_else(unknown, |s| s.to_string()),
+        stats.moltype.clone().unwrap_or_else(unknown),
+    )?;
+    writeln!(out, "datasets: {}", stats.n_datasets)?;
+    writeln!(out, "distinct hashes: {}", stats.n_hashes)?;
+    writeln!(out, "postings: {}", stats.n_postings)?;
+    writeln!(
+        out,
+        "estimated memory: {}",
+        bytes_fmt(stats.estimated_memory_bytes)
+    )?;
+
+    writeln!(out, "\nhashes by number of datasets:")?;
+    for bin in &stats.posting_histogram {
+        let range = if bin.min == bin.max {
+            bin.min.to_string()
+        } else {
+            format!("{}-{}", bin.min, bin.max)
+        };
+        writeln!(out, "  {:>13}: {}", range, bin.count)?;
+    }
+
+    if !stats.most_shared.is_empty() {
+        writeln!(out, "\nmost shared hashes:")?;
+        for shared in &stats.most_shared {
+            writeln!(out, "  {:>20}: {} datasets", shared.hash, shared.n_datasets)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn bytes_fmt(bytes: usize) -> String {
+    match bytes {
+        0..=1_000 => format!("{} B", bytes),
+        1_001..=1_000_000 => format!("{:.1} KB", bytes as f64 / 1e3),
+        1_000_001..=1_000_000_000 => format!("{:.1} MB", bytes as f64 / 1e6),
+        _ => format!("{:.1} GB", bytes as f64 / 1e9),
+    }
+}
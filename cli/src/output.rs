@@ -0,0 +1,261 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use greyhound_core::{GatherResult, SearchResult};
+
+use crate::error::Error;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Tsv,
+    Json,
+    Plain,
+}
+
+impl OutputFormat {
+    pub const VARIANTS: &'static [&'static str] = &["csv", "tsv", "json", "plain"];
+
+    /// Extension appended to per-query output files.
+    /// `plain` keeps the query filename unchanged, like previous versions.
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Csv => Some("csv"),
+            OutputFormat::Tsv => Some("tsv"),
+            OutputFormat::Json => Some("json"),
+            OutputFormat::Plain => None,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "json" => Ok(OutputFormat::Json),
+            "plain" => Ok(OutputFormat::Plain),
+            _ => Err(format!("unknown output format: {}", s)),
+        }
+    }
+}
+
+const GATHER_COLUMNS: &[&str] = &[
+    "intersect_bp",
+    "f_orig_query",
+    "f_match",
+    "f_unique_to_query",
+    "f_unique_weighted",
+    "average_abund",
+    "median_abund",
+    "std_abund",
+    "name",
+    "filename",
+    "md5",
+    "f_match_orig",
+    "unique_intersect_bp",
+    "gather_result_rank",
+    "remaining_bp",
+    "sum_weighted_found",
+    "total_weighted_hashes",
+    "estimated_depth",
+    "query_containment_ani",
+    "query_containment_ani_low",
+    "query_containment_ani_high",
+    "match_containment_ani",
+    "match_containment_ani_low",
+    "match_containment_ani_high",
+    "average_containment_ani",
+    "max_containment_ani",
+];
+
+/// A gather match, with the same columns as `sourmash gather -o`, plus the
+/// estimated depth of the match in the query and containment ANI estimates
+/// with 95% confidence intervals.
+#[derive(Serialize, Debug)]
+pub struct GatherRow {
+    /// Only set for results from several queries in one file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    query: Option<String>,
+    intersect_bp: usize,
+    f_orig_query: f64,
+    f_match: f64,
+    f_unique_to_query: f64,
+    f_unique_weighted: f64,
+    average_abund: f64,
+    median_abund: f64,
+    std_abund: f64,
+    name: String,
+    filename: String,
+    md5: String,
+    f_match_orig: f64,
+    unique_intersect_bp: usize,
+    gather_result_rank: usize,
+    remaining_bp: usize,
+    sum_weighted_found: u64,
+    total_weighted_hashes: u64,
+    estimated_depth: f64,
+    query_containment_ani: f64,
+    query_containment_ani_low: f64,
+    query_containment_ani_high: f64,
+    match_containment_ani: f64,
+    match_containment_ani_low: f64,
+    match_containment_ani_high: f64,
+    average_containment_ani: f64,
+    max_containment_ani: f64,
+}
+
+impl From<&GatherResult> for GatherRow {
+    fn from(m: &GatherResult) -> Self {
+        GatherRow {
+            query: None,
+            intersect_bp: m.intersect_bp(),
+            f_orig_query: m.f_orig_query(),
+            f_match: m.f_match(),
+            f_unique_to_query: m.f_unique_to_query(),
+            f_unique_weighted: m.f_unique_weighted(),
+            average_abund: m.abund_stats().mean(),
+            median_abund: m.abund_stats().median(),
+            std_abund: m.abund_stats().std(),
+            name: m.name().clone(),
+            filename: m.filename().clone(),
+            md5: m.md5().clone(),
+            f_match_orig: m.f_match_orig(),
+            unique_intersect_bp: m.unique_intersect_bp(),
+            gather_result_rank: m.gather_result_rank(),
+            remaining_bp: m.remaining_bp(),
+            sum_weighted_found: m.sum_weighted_found(),
+            total_weighted_hashes: m.total_weighted_hashes(),
+            estimated_depth: m.estimated_depth(),
+            query_containment_ani: m.query_containment_ani().ani(),
+            query_containment_ani_low: m.query_containment_ani().low(),
+            query_containment_ani_high: m.query_containment_ani().high(),
+            match_containment_ani: m.match_containment_ani().ani(),
+            match_containment_ani_low: m.match_containment_ani().low(),
+            match_containment_ani_high: m.match_containment_ani().high(),
+            average_containment_ani: m.average_containment_ani(),
+            max_containment_ani: m.max_containment_ani(),
+        }
+    }
+}
+
+impl GatherRow {
+    pub fn with_query(mut self, query: String) -> Self {
+        self.query = Some(query);
+        self
+    }
+}
+
+pub fn write_gather_results<W: Write>(
+    out: W,
+    matches: &[GatherResult],
+    format: OutputFormat,
+) -> Result<(), Error> {
+    let rows: Vec<GatherRow> = matches.iter().map(GatherRow::from).collect();
+    write_gather_rows(out, &rows, format, false)
+}
+
+/// Write results for several queries in one file. Rows must have a query,
+/// which is saved in the first column (or as a `query` field in JSON).
+pub fn write_combined_gather_results<W: Write>(
+    out: W,
+    rows: &[GatherRow],
+    format: OutputFormat,
+) -> Result<(), Error> {
+    write_gather_rows(out, rows, format, true)
+}
+
+fn write_gather_rows<W: Write>(
+    out: W,
+    rows: &[GatherRow],
+    format: OutputFormat,
+    combined: bool,
+) -> Result<(), Error> {
+    match format {
+        OutputFormat::Plain => {
+            let mut out = out;
+            for row in rows {
+                match &row.query {
+                    Some(query) if combined => writeln!(out, "{}\t{}", query, row.filename)?,
+                    _ => writeln!(out, "{}", row.filename)?,
+                }
+            }
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(out, &rows)?;
+        }
+        OutputFormat::Csv | OutputFormat::Tsv => {
+            let delimiter = if format == OutputFormat::Tsv {
+                b'\t'
+            } else {
+                b','
+            };
+            // Headers are written explicitly so queries without matches
+            // still produce a valid (empty) table.
+            let mut wtr = csv::WriterBuilder::new()
+                .delimiter(delimiter)
+                .has_headers(false)
+                .from_writer(out);
+            if combined {
+                wtr.write_record(std::iter::once(&"query").chain(GATHER_COLUMNS))?;
+            } else {
+                wtr.write_record(GATHER_COLUMNS)?;
+            }
+            for row in rows {
+                wtr.serialize(row)?;
+            }
+            wtr.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// A search match, with the same columns as `sourmash search -o`, plus the
+/// containment ANI estimate for the query with a 95% confidence interval.
+#[derive(Serialize, Debug)]
+pub struct SearchRow {
+    pub similarity: f64,
+    pub name: String,
+    pub filename: String,
+    pub md5: String,
+    pub query_containment_ani: f64,
+    pub query_containment_ani_low: f64,
+    pub query_containment_ani_high: f64,
+}
+
+impl From<&SearchResult> for SearchRow {
+    fn from(m: &SearchResult) -> Self {
+        SearchRow {
+            similarity: m.similarity(),
+            name: m.name().clone(),
+            filename: m.filename().clone(),
+            md5: m.md5().clone(),
+            query_containment_ani: m.query_containment_ani().ani(),
+            query_containment_ani_low: m.query_containment_ani().low(),
+            query_containment_ani_high: m.query_containment_ani().high(),
+        }
+    }
+}
+
+pub fn write_search_results<W: Write>(out: W, matches: &[SearchRow]) -> Result<(), Error> {
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(out);
+    wtr.write_record(&[
+        "similarity",
+        "name",
+        "filename",
+        "md5",
+        "query_containment_ani",
+        "query_containment_ani_low",
+        "query_containment_ani_high",
+    ])?;
+    for m in matches {
+        wtr.serialize(m)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use greyhound_core::{write_atomic, Picklist, SearchResult};
+use log::info;
+use serde::Serialize;
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::Sketch;
+
+use crate::error::Error;
+use crate::layout::{with_suffix, OutputLayout};
+use crate::{BatchOptions, QueryBatch};
+
+/// Options for `prefetch`, matching the CLI flags.
+#[derive(Debug)]
+pub struct PrefetchOptions {
+    pub threshold_bp: usize,
+    pub from_file: bool,
+    pub keep_going: bool,
+    pub fail_on_error: bool,
+    pub picklists: Vec<Picklist>,
+    pub save_matches: Option<PathBuf>,
+    pub output_layout: OutputLayout,
+}
+
+/// A dataset overlapping the query, with the same columns as
+/// `sourmash prefetch -o` when they are available in the index.
+#[derive(Serialize, Debug)]
+struct PrefetchRow<'a> {
+    intersect_bp: usize,
+    intersect_hashes: usize,
+    f_query_match: f64,
+    query_containment_ani: f64,
+    match_name: &'a str,
+    match_md5: &'a str,
+    match_filename: &'a str,
+}
+
+const PREFETCH_COLUMNS: &[&str] = &[
+    "intersect_bp",
+    "intersect_hashes",
+    "f_query_match",
+    "query_containment_ani",
+    "match_name",
+    "match_md5",
+    "match_filename",
+];
+
+/// List every dataset sharing at least `opts.threshold_bp` with each query,
+/// saving one CSV per query in `output`, named by `opts.output_layout`.
+///
+/// If `opts.save_matches` is set, the paths of all matched signatures (for
+/// any query) are saved there, one per line, so they can be used as the
+/// siglist for building a smaller index.
+pub fn prefetch<P: AsRef<Path>>(
+    queries_file: P,
+    siglist: P,
+    template: Sketch,
+    output: Option<P>,
+    opts: &PrefetchOptions,
+) -> Result<(), Error> {
+    let batch_opts = BatchOptions {
+        command: "prefetch",
+        from_file: opts.from_file,
+        threshold_bp: opts.threshold_bp,
+        keep_going: opts.keep_going,
+        fail_on_error: opts.fail_on_error,
+        picklists: &opts.picklists,
+        output_layout: opts.output_layout,
+    };
+    let (batch, failures) = QueryBatch::load(
+        queries_file.as_ref(),
+        siglist.as_ref(),
+        template,
+        output.as_ref().map(|p| p.as_ref()),
+        &batch_opts,
+    )?;
+    let revindex = &batch.revindex;
+    let scaled = match &batch.template {
+        Sketch::MinHash(mh) => mh.scaled() as usize,
+        _ => 1,
+    };
+
+    let matched = batch.run(failures, |query| -> Result<Vec<String>, Error> {
+        let results = if query.minhash.size() == 0 {
+            info!("Query {:?} has no hashes, skipping prefetch", query.path);
+            vec![]
+        } else {
+            let counter = revindex.counter_for(query.minhash, batch.picked.as_deref());
+            // Always require at least one shared hash
+            revindex.search_results(counter, batch.threshold.max(1), query.minhash)
+        };
+
+        info!("Saving {} overlapping datasets", results.len());
+        if let Some(parent) = query.out_stem.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_atomic(&with_suffix(query.out_stem, ".prefetch.csv"), |out| {
+            write_prefetch_results(out, &results, scaled)
+        })?;
+
+        Ok(results.iter().map(|r| r.filename().clone()).collect())
+    })?;
+
+    if let Some(path) = &opts.save_matches {
+        let matched: BTreeSet<String> = matched.into_iter().flatten().collect();
+        write_atomic(path, |out| -> Result<(), Error> {
+            for filename in &matched {
+                writeln!(out, "{}", filename)?;
+            }
+            Ok(())
+        })?;
+        info!(
+            "Saved {} matched signature paths to {:?}",
+            matched.len(),
+            path
+        );
+    }
+
+    info!("Finished");
+    Ok(())
+}
+
+fn write_prefetch_results<W: Write>(
+    out: W,
+    results: &[SearchResult],
+    scaled: usize,
+) -> Result<(), Error> {
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(out);
+    wtr.write_record(PREFETCH_COLUMNS)?;
+    for result in results {
+        wtr.serialize(PrefetchRow {
+            intersect_bp: result.intersect_hashes() * scaled,
+            intersect_hashes: result.intersect_hashes(),
+            f_query_match: result.containment(),
+            query_containment_ani: result.query_containment_ani().ani(),
+            match_name: result.name(),
+            match_md5: result.md5(),
+            match_filename: result.filename(),
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
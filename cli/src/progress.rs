@@ -0,0 +1,211 @@
+//! Progress reporting and run summaries for batch gathers.
+//!
+//! Progress is shown as a progress bar when stderr is a terminal, and as a
+//! JSON line on stderr every `REPORT_INTERVAL` otherwise (for example in
+//! cluster job logs).
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use greyhound_core::write_atomic;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+use crate::error::Error;
+
+pub const SUMMARY_FILE: &str = "run_summary.json";
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time spent in each phase of a run. Per-query phases are added up over
+/// all queries, so with several threads they can be longer than the run.
+#[derive(Default)]
+pub struct Timings {
+    phases: Mutex<BTreeMap<&'static str, Duration>>,
+}
+
+impl Timings {
+    pub fn add(&self, phase: &'static str, elapsed: Duration) {
+        *self.phases.lock().unwrap().entry(phase).or_default() += elapsed;
+    }
+
+    /// Run `f`, adding the time it took to `phase`.
+    pub fn time<T, F: FnOnce() -> T>(&self, phase: &'static str, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    pub fn as_secs(&self) -> BTreeMap<&'static str, f64> {
+        self.phases
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(phase, elapsed)| (*phase, elapsed.as_secs_f64()))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+struct ProgressReport {
+    event: &'static str,
+    done: usize,
+    failed: usize,
+    total: usize,
+    elapsed_secs: f64,
+    queries_per_sec: f64,
+    eta_secs: Option<f64>,
+    peak_memory_bytes: Option<u64>,
+}
+
+/// Counts of finished queries, reported as they complete.
+pub struct Progress {
+    total: usize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+    start: Instant,
+    bar: Option<ProgressBar>,
+    last_report: Mutex<Instant>,
+}
+
+impl Progress {
+    pub fn new(total: usize) -> Progress {
+        let bar = if atty::is(atty::Stream::Stderr) {
+            let bar = ProgressBar::new(total as u64);
+            bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{elapsed_precise} [{bar:40}] {pos}/{len} queries \
+                         ({per_sec}, ETA {eta}) {msg}",
+                    )
+                    .progress_chars("=> "),
+            );
+            Some(bar)
+        } else {
+            None
+        };
+
+        Progress {
+            total,
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            start: Instant::now(),
+            bar,
+            last_report: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Record a finished query.
+    pub fn inc(&self, ok: bool) {
+        if ok {
+            self.done.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+
+        match &self.bar {
+            Some(bar) => {
+                let mut msg = format!("{} failed", self.failed.load(Ordering::SeqCst));
+                if let Some(peak) = peak_memory() {
+                    msg.push_str(&format!(", peak memory {} MB", peak / 1_000_000));
+                }
+                bar.set_message(&msg);
+                bar.inc(1);
+            }
+            None => {
+                let mut last_report = self.last_report.lock().unwrap();
+                if last_report.elapsed() >= REPORT_INTERVAL {
+                    *last_report = Instant::now();
+                    self.report("progress");
+                }
+            }
+        }
+    }
+
+    pub fn finish(&self) {
+        match &self.bar {
+            Some(bar) => bar.finish(),
+            None => self.report("finished"),
+        }
+    }
+
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    fn report(&self, event: &'static str) {
+        let (done, failed) = (self.done(), self.failed());
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let finished = done + failed;
+        let queries_per_sec = if elapsed > 0. {
+            finished as f64 / elapsed
+        } else {
+            0.
+        };
+        let eta_secs = if queries_per_sec > 0. {
+            Some(self.total.saturating_sub(finished) as f64 / queries_per_sec)
+        } else {
+            None
+        };
+
+        let report = ProgressReport {
+            event,
+            done,
+            failed,
+            total: self.total,
+            elapsed_secs: elapsed,
+            queries_per_sec,
+            eta_secs,
+            peak_memory_bytes: peak_memory(),
+        };
+        if let Ok(line) = serde_json::to_string(&report) {
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// Machine-readable summary of a gather run.
+#[derive(Serialize)]
+pub struct RunSummary {
+    pub queries: usize,
+    pub done: usize,
+    pub failed: usize,
+    pub empty: usize,
+    pub resumed: usize,
+    pub wall_time_secs: f64,
+    pub peak_memory_bytes: Option<u64>,
+    /// Seconds spent in each phase
+    pub phases: BTreeMap<&'static str, f64>,
+}
+
+impl RunSummary {
+    pub fn save(&self, outdir: &Path) -> Result<(), Error> {
+        write_atomic(&outdir.join(SUMMARY_FILE), |out| {
+            serde_json::to_writer_pretty(out, self)?;
+            Ok(())
+        })
+    }
+}
+
+/// Peak resident memory of this process. Only available on Linux.
+pub fn peak_memory() -> Option<u64> {
+    let status = File::open("/proc/self/status").ok()?;
+    for line in BufReader::new(status).lines() {
+        let line = line.ok()?;
+        if let Some(value) = line.strip_prefix("VmHWM:") {
+            let kb: u64 = value.trim().trim_end_matches("kB").trim().parse().ok()?;
+            return Some(kb * 1024);
+        }
+    }
+    None
+}
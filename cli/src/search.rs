@@ -0,0 +1,71 @@
+use std::path::Path;
+
+use greyhound_core::{write_atomic, Picklist};
+use log::info;
+use sourmash::sketch::Sketch;
+
+use crate::error::Error;
+use crate::layout::{with_suffix, OutputLayout};
+use crate::output::{write_search_results, SearchRow};
+use crate::{BatchOptions, QueryBatch};
+
+/// Options for `search`, matching the CLI flags.
+#[derive(Debug)]
+pub struct SearchOptions {
+    pub threshold: f64,
+    pub containment: bool,
+    pub from_file: bool,
+    pub keep_going: bool,
+    pub fail_on_error: bool,
+    pub picklists: Vec<Picklist>,
+    pub output_layout: OutputLayout,
+}
+
+/// Find datasets with a Jaccard similarity (or containment of the query) of
+/// at least `opts.threshold` with each query, saving one CSV per query in
+/// `output`, named by `opts.output_layout`.
+pub fn search<P: AsRef<Path>>(
+    queries_file: P,
+    siglist: P,
+    template: Sketch,
+    output: Option<P>,
+    opts: &SearchOptions,
+) -> Result<(), Error> {
+    let batch_opts = BatchOptions {
+        command: "search",
+        from_file: opts.from_file,
+        threshold_bp: 0,
+        keep_going: opts.keep_going,
+        fail_on_error: opts.fail_on_error,
+        picklists: &opts.picklists,
+        output_layout: opts.output_layout,
+    };
+    let (batch, failures) = QueryBatch::load(
+        queries_file.as_ref(),
+        siglist.as_ref(),
+        template,
+        output.as_ref().map(|p| p.as_ref()),
+        &batch_opts,
+    )?;
+    let revindex = &batch.revindex;
+
+    batch.run(failures, |query| {
+        info!("Build counter for query");
+        let counter = revindex.counter_for(query.minhash, batch.picked.as_deref());
+        let results = revindex.search(counter, !opts.containment, opts.threshold, query.minhash)?;
+        let matches: Vec<SearchRow> = results.iter().map(SearchRow::from).collect();
+
+        info!("Saving {} matches", matches.len());
+        if let Some(parent) = query.out_stem.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        write_atomic(&with_suffix(query.out_stem, ".csv"), |out| {
+            write_search_results(out, &matches)
+        })?;
+        info!("Finishing query {:?}", query.path);
+        Ok(())
+    })?;
+    info!("Finished");
+
+    Ok(())
+}
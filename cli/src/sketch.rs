@@ -0,0 +1,125 @@
+use std::path::Path;
+
+use greyhound_core::write_atomic;
+use log::info;
+use needletail::{parse_fastx_file, Sequence};
+use rayon::prelude::*;
+use sourmash::cmd::ComputeParameters;
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::Signature;
+use sourmash::sketch::Sketch;
+
+use crate::error::Error;
+use crate::layout::{common_base, with_suffix, OutputLayout, OutputNames};
+use crate::read_paths;
+
+/// Build a Scaled MinHash signature from a FASTA/FASTQ file.
+pub fn sketch_file<P: AsRef<Path>>(
+    path: P,
+    ksize: u8,
+    scaled: usize,
+    track_abundance: bool,
+) -> Result<Signature, Error> {
+    let params = ComputeParameters::builder()
+        .ksizes(vec![ksize as u32])
+        .num_hashes(0)
+        .scaled(scaled as u64)
+        .track_abundance(track_abundance)
+        .build();
+    let mut sig = Signature::from_params(&params);
+
+    let sketching_error = |e: needletail::errors::ParseError| Error::Sketching {
+        path: path.as_ref().into(),
+        reason: e.to_string(),
+    };
+
+    let filename = path.as_ref().to_string_lossy();
+    let mut parser = parse_fastx_file(path.as_ref()).map_err(sketching_error)?;
+    let mut name = None;
+    while let Some(record) = parser.next() {
+        let record = record.map_err(sketching_error)?;
+        if name.is_none() {
+            name = Some(String::from_utf8_lossy(record.id()).into_owned());
+        }
+        let norm_seq = record.normalize(true);
+        sig.add_sequence(&norm_seq, true)?;
+    }
+
+    sig.set_filename(&filename);
+    sig.set_name(name.as_deref().unwrap_or(&filename));
+    Ok(sig)
+}
+
+/// Sketch a query file with the same parameters as the index template.
+pub fn sketch_for_template<P: AsRef<Path>>(path: P, template: &Sketch) -> Result<Signature, Error> {
+    match template {
+        Sketch::MinHash(mh) if mh.hash_function() == HashFunctions::murmur64_DNA => sketch_file(
+            path,
+            mh.ksize() as u8,
+            mh.scaled() as usize,
+            mh.track_abundance(),
+        ),
+        _ => Err(Error::Sketching {
+            path: path.as_ref().into(),
+            reason: "only DNA MinHash templates can be sketched".into(),
+        }),
+    }
+}
+
+/// Sketch all FASTA/FASTQ files listed in `inputs_file`, saving one
+/// signature per file in `outdir` as `<output name>.sig`, named by
+/// `output_layout`.
+pub fn sketch<P: AsRef<Path>>(
+    inputs_file: P,
+    ksize: u8,
+    scaled: usize,
+    track_abundance: bool,
+    outdir: P,
+    output_layout: OutputLayout,
+) -> Result<(), Error> {
+    output_layout.require_per_query("sketch")?;
+
+    let inputs = read_paths(inputs_file)?;
+    info!("Sketching {} files", inputs.len());
+
+    let outdir = outdir.as_ref();
+    std::fs::create_dir_all(outdir)?;
+
+    let base = common_base(&inputs);
+    let outputs = OutputNames::default();
+    // Fail before sketching if two inputs would overwrite each other
+    if output_layout.from_paths() {
+        for input in &inputs {
+            outputs.claim_stem(output_layout, outdir, &base, input, None)?;
+        }
+    }
+
+    inputs
+        .par_iter()
+        .map(|input| -> Result<(), Error> {
+            let sig = sketch_file(input, ksize, scaled, track_abundance)?;
+
+            let (name, md5) = (sig.name(), sig.md5sum());
+            let stem = outputs.claim_stem(
+                output_layout,
+                outdir,
+                &base,
+                input,
+                Some((name.as_str(), md5.as_str())),
+            )?;
+            if let Some(parent) = stem.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let path = with_suffix(&stem, ".sig");
+            write_atomic(&path, |out| -> Result<(), Error> {
+                serde_json::to_writer(out, &[&sig])?;
+                Ok(())
+            })?;
+            info!("Saved {:?}", path);
+            Ok(())
+        })
+        .collect::<Result<(), _>>()?;
+
+    info!("Finished");
+    Ok(())
+}
@@ -0,0 +1,189 @@
+//! Gathering queries streamed from stdin, for use in pipelines.
+//!
+//! Signatures are read as a stream of JSON values (sourmash signature files,
+//! concatenated or one per line, optionally compressed), and each query is
+//! gathered as soon as it is read, with at most a few queries in memory at
+//! once. Results are written to stdout as newline-delimited JSON, one
+//! object per query, in the order queries finish.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sourmash::signature::Signature;
+use sourmash::sketch::Sketch;
+
+use crate::error::Error;
+use crate::gather::{empty_reason, gather_query, GatherOptions, Outcome, QueryRecord};
+use crate::layout::OutputLayout;
+use crate::output::{GatherRow, OutputFormat};
+use crate::progress::Timings;
+use crate::{open_index, resolve_index, select_query, IndexOptions};
+
+/// Query path used for reading queries from stdin.
+pub const STDIN: &str = "-";
+
+/// A JSON value in the input: a signature file holds a list of signatures,
+/// but single signatures are accepted too.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SigValue {
+    Many(Vec<Signature>),
+    One(Signature),
+}
+
+#[derive(Serialize)]
+struct QueryResults {
+    query: String,
+    query_md5: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Set for queries without hashes, as in `empty_queries.csv`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<QueryRecord>,
+    matches: Vec<GatherRow>,
+}
+
+/// Gather each query read from stdin against the index at `siglist`.
+pub fn gather_stdin(siglist: &Path, template: Sketch, opts: &GatherOptions) -> Result<(), Error> {
+    if opts.from_file {
+        return Err(Error::Unsupported(
+            "--from-file can't be used with queries from stdin".into(),
+        ));
+    }
+    if opts.resume {
+        return Err(Error::Unsupported(
+            "--resume can't be used with queries from stdin".into(),
+        ));
+    }
+    if opts.output_format != OutputFormat::Plain || opts.output_layout != OutputLayout::Flat {
+        warn!(
+            "Results for queries from stdin are written to stdout as JSON lines, \
+             ignoring --output-format and --output-layout"
+        );
+    }
+
+    let (index_path, template) = resolve_index(siglist, &template)?;
+    let index_opts = IndexOptions {
+        mmap: opts.mmap,
+        picklists: &opts.picklists,
+        ..Default::default()
+    };
+    let (mut revindex, picked) = open_index(&index_path, &template, &index_opts)?;
+    if let Some(max_memory) = opts.max_memory {
+        revindex.set_cache(max_memory);
+    }
+
+    // Parsing happens in its own thread, and the bounded channel keeps it
+    // from reading much further ahead than the queries being gathered.
+    let (tx, rx) = sync_channel::<Result<Signature, Error>>(rayon::current_num_threads());
+    std::thread::spawn(move || {
+        let rdr = match niffler::get_reader(Box::new(std::io::stdin())) {
+            Ok((rdr, _)) => rdr,
+            // Nothing (or too little to be a signature) was sent
+            Err(niffler::Error::FileTooShort) => return,
+            Err(e) => {
+                let _ = tx.send(Err(e.into()));
+                return;
+            }
+        };
+
+        for value in serde_json::Deserializer::from_reader(rdr).into_iter::<SigValue>() {
+            let sigs = match value {
+                Ok(SigValue::Many(sigs)) => sigs,
+                Ok(SigValue::One(sig)) => vec![sig],
+                Err(e) => {
+                    let _ = tx.send(Err(e.into()));
+                    return;
+                }
+            };
+            for sig in sigs {
+                // The receiver is gone if gathering failed
+                if tx.send(Ok(sig)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let timings = Timings::default();
+    let processed = AtomicUsize::new(0);
+    let empty = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    rx.into_iter()
+        .par_bridge()
+        .try_for_each(|sig| -> Result<(), Error> {
+            let sig = sig?;
+            let name = sig.name();
+
+            let query = select_query(std::slice::from_ref(&sig), &template, Path::new(STDIN))
+                .map(|(_, query)| query);
+            let mut results = QueryResults {
+                query: name.clone(),
+                query_md5: String::new(),
+                error: None,
+                status: None,
+                matches: vec![],
+            };
+            match query.and_then(|query| {
+                results.query_md5 = query.md5sum();
+                gather_query(
+                    &revindex,
+                    picked.as_deref(),
+                    &query,
+                    opts.threshold_bp,
+                    &timings,
+                )
+            }) {
+                Ok((matches, Outcome::Done)) => {
+                    results.matches = matches.iter().map(GatherRow::from).collect()
+                }
+                Ok((_, Outcome::Empty)) => {
+                    empty.fetch_add(1, Ordering::SeqCst);
+                    results.status = Some(QueryRecord {
+                        query: name.clone(),
+                        reason: empty_reason(&template),
+                    });
+                }
+                Err(e) if opts.keep_going => {
+                    warn!("Query {} failed: {}", name, e);
+                    failed.fetch_add(1, Ordering::SeqCst);
+                    results.error = Some(e.to_string());
+                }
+                Err(e) => {
+                    return Err(Error::Query {
+                        path: name.into(),
+                        source: Box::new(e),
+                    })
+                }
+            }
+
+            let mut line = serde_json::to_vec(&results)?;
+            line.push(b'\n');
+            let stdout = std::io::stdout();
+            let mut out = stdout.lock();
+            out.write_all(&line)?;
+            // Downstream tools can start on each query right away
+            out.flush()?;
+
+            processed.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        })?;
+
+    let failed = failed.into_inner();
+    info!(
+        "Finished {} queries from stdin, {} had no hashes, {} failed",
+        processed.into_inner(),
+        empty.into_inner(),
+        failed
+    );
+    debug!("Time per phase: {:?}", timings.as_secs());
+    if failed > 0 && opts.fail_on_error {
+        return Err(Error::FailedQueries(failed));
+    }
+    Ok(())
+}
@@ -0,0 +1,274 @@
+//! Removing the hashes of host or contaminant matches from queries.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use greyhound_core::{is_sequence_file, write_atomic, DatasetID, Picklist};
+use log::info;
+use needletail::parser::Format;
+use needletail::{parse_fastx_file, Sequence};
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::error::Error;
+use crate::layout::{with_suffix, OutputLayout};
+use crate::{BatchOptions, QueryBatch};
+
+/// Options for `subtract`, matching the CLI flags.
+#[derive(Debug)]
+pub struct SubtractOptions {
+    pub threshold_bp: usize,
+    pub from_file: bool,
+    pub keep_going: bool,
+    pub fail_on_error: bool,
+    pub picklists: Vec<Picklist>,
+    pub filter_reads: bool,
+    pub max_removed_fraction: f64,
+    pub output_layout: OutputLayout,
+}
+
+/// Remove from each query the hashes shared with the selected matches, and
+/// save the remaining sketch as `<output name>.subtracted.sig` in `output`,
+/// named by `opts.output_layout`.
+///
+/// Matches are the gather results for the query sharing at least
+/// `opts.threshold_bp` with it or, if picklists are provided, all datasets
+/// passing them that share hashes with the query.
+pub fn subtract<P: AsRef<Path>>(
+    queries_file: P,
+    siglist: P,
+    template: Sketch,
+    output: Option<P>,
+    opts: &SubtractOptions,
+) -> Result<(), Error> {
+    let batch_opts = BatchOptions {
+        command: "subtract",
+        from_file: opts.from_file,
+        threshold_bp: opts.threshold_bp,
+        keep_going: opts.keep_going,
+        fail_on_error: opts.fail_on_error,
+        picklists: &opts.picklists,
+        output_layout: opts.output_layout,
+    };
+    let (batch, failures) = QueryBatch::load(
+        queries_file.as_ref(),
+        siglist.as_ref(),
+        template,
+        output.as_ref().map(|p| p.as_ref()),
+        &batch_opts,
+    )?;
+    let revindex = &batch.revindex;
+
+    batch.run(failures, |query| {
+        let selected: HashSet<DatasetID> = match &batch.picked {
+            Some(picked) => revindex
+                .counter_for(query.minhash, Some(picked))
+                .into_iter()
+                .map(|(dataset_id, _)| dataset_id)
+                .collect(),
+            None => {
+                let counter = revindex.counter_for(query.minhash, None);
+                revindex
+                    .gather(counter, batch.threshold.max(1), query.minhash)?
+                    .iter()
+                    .map(|m| m.dataset_id())
+                    .collect()
+            }
+        };
+
+        let removed = revindex.shared_hashes(query.minhash, &selected);
+        let mut remaining = query.minhash.clone();
+        remaining.remove_many(&removed)?;
+        info!(
+            "Removed {} of {} hashes from {:?}, shared with {} matches",
+            removed.len(),
+            query.minhash.size(),
+            query.path,
+            selected.len()
+        );
+
+        let mut sig = Signature::default();
+        sig.set_name(query.name);
+        sig.set_filename(&query.path.to_string_lossy());
+        sig.push(Sketch::MinHash(remaining));
+
+        if let Some(parent) = query.out_stem.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let path = with_suffix(query.out_stem, ".subtracted.sig");
+        write_atomic(&path, |out| -> Result<(), Error> {
+            serde_json::to_writer(out, &[&sig])?;
+            Ok(())
+        })?;
+        info!("Saved {:?}", path);
+
+        if opts.filter_reads && query.path.is_file() && is_sequence_file(query.path)? {
+            filter_reads(
+                query.path,
+                &batch.template,
+                &removed,
+                opts.max_removed_fraction,
+                query.out_stem,
+            )?;
+        }
+        Ok(())
+    })?;
+
+    info!("Finished");
+    Ok(())
+}
+
+/// Save the reads in `path` to `<out_stem>.filtered.fa` (or `.fq`),
+/// dropping reads where more than `max_removed_fraction` of their hashes are
+/// in `removed`.
+///
+/// Only k-mers sampled at the template scaled can be compared, so reads
+/// without any are kept. Short reads need a small scaled to be filtered.
+fn filter_reads(
+    path: &Path,
+    template: &Sketch,
+    removed: &[u64],
+    max_removed_fraction: f64,
+    out_stem: &Path,
+) -> Result<(), Error> {
+    let sketching_error = |e: needletail::errors::ParseError| Error::Sketching {
+        path: path.into(),
+        reason: e.to_string(),
+    };
+
+    let empty_mh = match template {
+        Sketch::MinHash(mh) if mh.hash_function() == HashFunctions::murmur64_DNA => {
+            KmerMinHash::builder()
+                .num(0u32)
+                .ksize(mh.ksize() as u32)
+                .hash_function(HashFunctions::murmur64_DNA)
+                .max_hash(mh.max_hash())
+                .build()
+        }
+        _ => {
+            return Err(Error::Sketching {
+                path: path.into(),
+                reason: "only DNA MinHash templates can be used for filtering reads".into(),
+            })
+        }
+    };
+
+    // The output extension follows the input format, from the first record
+    let format = match parse_fastx_file(path).map_err(sketching_error)?.next() {
+        Some(record) => record.map_err(sketching_error)?.format(),
+        None => {
+            info!("No reads in {:?}", path);
+            return Ok(());
+        }
+    };
+    let extension = match format {
+        Format::Fasta => "fa",
+        Format::Fastq => "fq",
+    };
+    let out_path = with_suffix(out_stem, &format!(".filtered.{}", extension));
+
+    let (mut kept, mut dropped) = (0, 0);
+    write_atomic(&out_path, |out| -> Result<(), Error> {
+        let mut parser = parse_fastx_file(path).map_err(sketching_error)?;
+        while let Some(record) = parser.next() {
+            let record = record.map_err(sketching_error)?;
+
+            let mut read_mh = empty_mh.clone();
+            read_mh.add_sequence(&record.normalize(true), true)?;
+            let hashes = read_mh.mins();
+            let n_removed = hashes
+                .iter()
+                .filter(|hash| removed.binary_search(hash).is_ok())
+                .count();
+            if !hashes.is_empty() && n_removed as f64 / hashes.len() as f64 > max_removed_fraction {
+                dropped += 1;
+                continue;
+            }
+
+            record.write(out, None).map_err(sketching_error)?;
+            kept += 1;
+        }
+        Ok(())
+    })?;
+
+    info!(
+        "Kept {} reads from {:?} in {:?}, dropped {}",
+        kept, path, out_path, dropped
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use greyhound_core::load_signatures;
+    use sourmash::sketch::minhash::max_hash_for_scaled;
+
+    fn template() -> Sketch {
+        Sketch::MinHash(
+            KmerMinHash::builder()
+                .num(0u32)
+                .ksize(31)
+                .max_hash(max_hash_for_scaled(1000))
+                .build(),
+        )
+    }
+
+    fn save_sig(path: &Path, hashes: &[u64]) {
+        let mut mh = match template() {
+            Sketch::MinHash(mh) => mh,
+            _ => unreachable!(),
+        };
+        mh.add_many(hashes).unwrap();
+
+        let mut sig = Signature::default();
+        sig.set_name(&path.file_name().unwrap().to_string_lossy());
+        sig.push(Sketch::MinHash(mh));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_atomic(path, |out| -> Result<(), Error> {
+            serde_json::to_writer(out, &[&sig])?;
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn keep_matches_below_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let query: Vec<u64> = (1..=20).collect();
+        save_sig(&dir.path().join("queries/query.sig"), &query);
+        // 3 shared hashes at scaled 1000 are 3000 bp, 10 are 10000 bp
+        save_sig(&dir.path().join("refs/small.sig"), &[1, 2, 3, 100, 101]);
+        save_sig(&dir.path().join("refs/large.sig"), &query[10..]);
+
+        let opts = SubtractOptions {
+            threshold_bp: 5000,
+            from_file: true,
+            keep_going: false,
+            fail_on_error: false,
+            picklists: vec![],
+            filter_reads: false,
+            max_removed_fraction: 0.5,
+            output_layout: OutputLayout::Flat,
+        };
+        let outdir = dir.path().join("outputs");
+        subtract(
+            dir.path().join("queries"),
+            dir.path().join("refs"),
+            template(),
+            Some(outdir.clone()),
+            &opts,
+        )
+        .unwrap();
+
+        let sigs = load_signatures(outdir.join("query.sig.subtracted.sig")).unwrap();
+        let remaining = match &sigs[0].sketches()[0] {
+            Sketch::MinHash(mh) => mh.mins(),
+            _ => unreachable!(),
+        };
+        assert_eq!(remaining, query[..10].to_vec());
+    }
+}
@@ -0,0 +1,566 @@
+//! Taxonomic summaries of gather results, using a lineage CSV keyed by
+//! accession (GTDB or NCBI style).
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use greyhound_core::write_atomic;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::layout::OutputNames;
+use crate::read_paths;
+
+pub const RANKS: &[&str] = &[
+    "superkingdom",
+    "phylum",
+    "class",
+    "order",
+    "family",
+    "genus",
+    "species",
+];
+
+/// Rank codes used in Kraken reports, in the same order as `RANKS`.
+const KRAKEN_CODES: &[&str] = &["D", "P", "C", "O", "F", "G", "S"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaxFormat {
+    Csv,
+    Kraken,
+    Cami,
+}
+
+impl TaxFormat {
+    pub const VARIANTS: &'static [&'static str] = &["csv", "kraken", "cami"];
+
+    fn extension(&self) -> &'static str {
+        match self {
+            TaxFormat::Csv => "summarized.csv",
+            TaxFormat::Kraken => "kreport.txt",
+            TaxFormat::Cami => "profile.txt",
+        }
+    }
+}
+
+impl FromStr for TaxFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(TaxFormat::Csv),
+            "kraken" => Ok(TaxFormat::Kraken),
+            "cami" => Ok(TaxFormat::Cami),
+            _ => Err(format!("unknown taxonomy output format: {}", s)),
+        }
+    }
+}
+
+/// Names for each rank in `RANKS`. Missing ranks are empty.
+type Lineage = Vec<String>;
+
+/// Lineages keyed by accession, and by accession without version.
+/// GTDB `GB_`/`RS_` prefixes are removed from keys, so accessions match
+/// with or without them.
+pub struct Taxonomy {
+    lineages: HashMap<String, Lineage>,
+    unversioned: HashMap<String, Lineage>,
+}
+
+impl Taxonomy {
+    /// Load a lineage CSV. The first column is the accession, and ranks are
+    /// read from columns with the rank names (`domain` is accepted for
+    /// `superkingdom`).
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Taxonomy, Error> {
+        let mut rdr = csv::Reader::from_path(&path)?;
+        let headers = rdr.headers()?.clone();
+
+        let columns: Vec<Option<usize>> = RANKS
+            .iter()
+            .map(|rank| {
+                headers.iter().position(|header| {
+                    let header = header.to_lowercase();
+                    header == *rank || (*rank == "superkingdom" && header == "domain")
+                })
+            })
+            .collect();
+        if columns.iter().all(Option::is_none) {
+            return Err(Error::Taxonomy(format!(
+                "no rank columns found in {:?}",
+                path.as_ref()
+            )));
+        }
+
+        let mut lineages = HashMap::new();
+        let mut unversioned = HashMap::new();
+        for record in rdr.records() {
+            let record = record?;
+            let ident = strip_gtdb_prefix(record.get(0).unwrap_or("")).to_string();
+            let lineage: Lineage = columns
+                .iter()
+                .map(|column| column.and_then(|c| record.get(c)).unwrap_or("").to_string())
+                .collect();
+
+            unversioned.insert(strip_version(&ident).to_string(), lineage.clone());
+            lineages.insert(ident, lineage);
+        }
+
+        info!("Loaded {} lineages", lineages.len());
+        Ok(Taxonomy {
+            lineages,
+            unversioned,
+        })
+    }
+
+    /// Find the lineage for a signature name, using the first word as the
+    /// accession.
+    fn lineage(&self, name: &str) -> Option<&Lineage> {
+        let ident = strip_gtdb_prefix(name.split_whitespace().next().unwrap_or(""));
+        self.lineages
+            .get(ident)
+            .or_else(|| self.unversioned.get(strip_version(ident)))
+    }
+}
+
+fn strip_gtdb_prefix(ident: &str) -> &str {
+    ident
+        .strip_prefix("GB_")
+        .or_else(|| ident.strip_prefix("RS_"))
+        .unwrap_or(ident)
+}
+
+fn strip_version(ident: &str) -> &str {
+    ident.split('.').next().unwrap_or("")
+}
+
+/// Columns used from `gather --output-format csv` (or `sourmash gather -o`).
+#[derive(Deserialize, Debug)]
+struct GatherCsvRow {
+    /// Only present in results for several queries (`--output-layout combined`)
+    #[serde(default)]
+    query: Option<String>,
+    name: String,
+    f_unique_weighted: f64,
+    unique_intersect_bp: usize,
+}
+
+/// Fraction of the query assigned to one lineage at one rank.
+#[derive(Serialize, Debug)]
+struct RankSummary {
+    query_name: String,
+    rank: &'static str,
+    fraction: f64,
+    lineage: String,
+    bp_match_at_rank: usize,
+}
+
+/// Per-rank totals for one query: lineage (up to the rank) -> (fraction, bp).
+struct QuerySummary {
+    query_name: String,
+    ranks: Vec<Vec<(Lineage, f64, usize)>>,
+}
+
+impl QuerySummary {
+    fn new(query_name: String, rows: &[GatherCsvRow], taxonomy: &Taxonomy) -> QuerySummary {
+        let mut missing = 0;
+        let mut totals: Vec<HashMap<Lineage, (f64, usize)>> = vec![HashMap::new(); RANKS.len()];
+        for row in rows {
+            let lineage = match taxonomy.lineage(&row.name) {
+                Some(lineage) => lineage,
+                None => {
+                    missing += 1;
+                    continue;
+                }
+            };
+
+            for (rank, rank_totals) in totals.iter_mut().enumerate() {
+                // Matches without a name at this rank stay unclassified
+                if lineage[rank].is_empty() {
+                    continue;
+                }
+                let entry = rank_totals
+                    .entry(lineage[..=rank].to_vec())
+                    .or_insert((0., 0));
+                entry.0 += row.f_unique_weighted;
+                entry.1 += row.unique_intersect_bp;
+            }
+        }
+
+        if missing > 0 {
+            warn!(
+                "{}: {} matches have no lineage and are reported as unclassified",
+                query_name, missing
+            );
+        }
+
+        let ranks = totals
+            .into_iter()
+            .map(|rank_totals| {
+                let mut rank_totals: Vec<_> = rank_totals
+                    .into_iter()
+                    .map(|(lineage, (fraction, bp))| (lineage, fraction, bp))
+                    .collect();
+                rank_totals.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+                rank_totals
+            })
+            .collect();
+
+        QuerySummary { query_name, ranks }
+    }
+
+    fn unclassified(&self, rank: usize) -> f64 {
+        let classified: f64 = self.ranks[rank].iter().map(|(_, f, _)| f).sum();
+        (1. - classified).max(0.)
+    }
+
+    fn write_csv<W: Write>(&self, out: W) -> Result<(), Error> {
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(out);
+        wtr.write_record(&[
+            "query_name",
+            "rank",
+            "fraction",
+            "lineage",
+            "bp_match_at_rank",
+        ])?;
+
+        for (rank, rank_totals) in self.ranks.iter().enumerate() {
+            for (lineage, fraction, bp) in rank_totals {
+                wtr.serialize(RankSummary {
+                    query_name: self.query_name.clone(),
+                    rank: RANKS[rank],
+                    fraction: *fraction,
+                    lineage: lineage.join(";"),
+                    bp_match_at_rank: *bp,
+                })?;
+            }
+            wtr.serialize(RankSummary {
+                query_name: self.query_name.clone(),
+                rank: RANKS[rank],
+                fraction: self.unclassified(rank),
+                lineage: "unclassified".into(),
+                bp_match_at_rank: 0,
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Kraken-style report: percentage, bp assigned to the clade, bp
+    /// assigned directly to the taxon, rank code, taxid (not available) and
+    /// indented name, in depth-first order.
+    fn write_kraken<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        let unclassified = self.unclassified(0);
+        writeln!(out, "{:.2}\t0\t0\tU\t\tunclassified", unclassified * 100.)?;
+        self.write_kraken_children(&mut out, 0, &[])?;
+        out.flush()?;
+        Ok(())
+    }
+
+    fn write_kraken_children<W: Write>(
+        &self,
+        out: &mut W,
+        rank: usize,
+        parent: &[String],
+    ) -> Result<(), Error> {
+        if rank >= RANKS.len() {
+            return Ok(());
+        }
+
+        for (lineage, fraction, bp) in &self.ranks[rank] {
+            if lineage[..rank] != *parent {
+                continue;
+            }
+
+            let children_bp: usize = self
+                .ranks
+                .get(rank + 1)
+                .map(|next| {
+                    next.iter()
+                        .filter(|(child, _, _)| child[..=rank] == lineage[..])
+                        .map(|(_, _, bp)| bp)
+                        .sum()
+                })
+                .unwrap_or(0);
+
+            writeln!(
+                out,
+                "{:.2}\t{}\t{}\t{}\t\t{}{}",
+                fraction * 100.,
+                bp,
+                bp.saturating_sub(children_bp),
+                KRAKEN_CODES[rank],
+                "  ".repeat(rank),
+                lineage[rank]
+            )?;
+            self.write_kraken_children(out, rank + 1, lineage)?;
+        }
+        Ok(())
+    }
+
+    /// CAMI profiling format. Taxids are not available, so names are used
+    /// in the TAXID and TAXPATH columns.
+    fn write_cami<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        writeln!(out, "@SampleID:{}", self.query_name)?;
+        writeln!(out, "@Version:0.10.0")?;
+        writeln!(out, "@Ranks:{}", RANKS.join("|"))?;
+        writeln!(out)?;
+        writeln!(out, "@@TAXID\tRANK\tTAXPATH\tTAXPATHSN\tPERCENTAGE")?;
+
+        for (rank, rank_totals) in self.ranks.iter().enumerate() {
+            for (lineage, fraction, _) in rank_totals {
+                let path = lineage.join("|");
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{:.5}",
+                    lineage[rank],
+                    RANKS[rank],
+                    path,
+                    path,
+                    fraction * 100.
+                )?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Gather results for one query.
+struct QueryRows {
+    /// Query path from the `query` column or, for results of a single query,
+    /// the gather CSV
+    source: PathBuf,
+    /// Query filename, used for naming the summary
+    name: String,
+    rows: Vec<GatherCsvRow>,
+}
+
+/// Summarize each gather result in `gather_results` (a file with one CSV
+/// path per line), saving one summary per query in `outdir`. Results for
+/// several queries in one CSV are split by the `query` column.
+pub fn summarize<P: AsRef<Path>>(
+    gather_results: P,
+    taxonomy: P,
+    format: TaxFormat,
+    outdir: Option<P>,
+) -> Result<(), Error> {
+    let results = read_paths(gather_results)?;
+    let taxonomy = Taxonomy::from_path(taxonomy)?;
+
+    let outdir: PathBuf = match outdir {
+        Some(p) => p.as_ref().into(),
+        None => PathBuf::from("outputs"),
+    };
+    std::fs::create_dir_all(&outdir)?;
+
+    // Queries with the same filename (from different directories or
+    // different gather results) would overwrite each other's summaries
+    let outputs = OutputNames::default();
+    for path in results {
+        let rows = csv::Reader::from_path(&path)?
+            .deserialize()
+            .collect::<Result<Vec<GatherCsvRow>, _>>()?;
+
+        for query in group_by_query(&path, rows) {
+            let summary = QuerySummary::new(query.name, &query.rows, &taxonomy);
+
+            let mut filename = summary.query_name.clone();
+            filename.push('.');
+            filename.push_str(format.extension());
+            let output = outdir.join(&filename);
+            outputs.claim(&output, &query.source)?;
+            write_atomic(&output, |out| match format {
+                TaxFormat::Csv => summary.write_csv(out),
+                TaxFormat::Kraken => summary.write_kraken(out),
+                TaxFormat::Cami => summary.write_cami(out),
+            })?;
+            info!("Saved summary for {:?} from {:?}", query.source, path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Split rows from a gather CSV by query, in order of first appearance.
+/// Rows without a `query` column are all for the query the CSV is named
+/// after.
+fn group_by_query(path: &Path, rows: Vec<GatherCsvRow>) -> Vec<QueryRows> {
+    let file_query = || QueryRows {
+        source: path.into(),
+        name: path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .trim_end_matches(".csv")
+            .to_string(),
+        rows: vec![],
+    };
+
+    let mut groups: Vec<QueryRows> = vec![];
+    // Keyed by the full query path, so queries with the same filename are
+    // kept apart
+    let mut positions: HashMap<Option<String>, usize> = HashMap::new();
+    for row in rows {
+        let pos = *positions.entry(row.query.clone()).or_insert_with(|| {
+            groups.push(match &row.query {
+                Some(query) => QueryRows {
+                    source: query.into(),
+                    name: Path::new(query)
+                        .file_name()
+                        .map_or_else(|| query.clone(), |name| name.to_string_lossy().into()),
+                    rows: vec![],
+                },
+                None => file_query(),
+            });
+            groups.len() - 1
+        });
+        groups[pos].rows.push(row);
+    }
+
+    if groups.is_empty() {
+        // Queries without matches are still summarized as unclassified
+        groups.push(file_query());
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lineage(names: &[&str]) -> Lineage {
+        let mut lineage: Lineage = names.iter().map(|n| n.to_string()).collect();
+        lineage.resize(RANKS.len(), String::new());
+        lineage
+    }
+
+    fn taxonomy() -> Taxonomy {
+        let genus = ["Bacteria", "A", "Ac", "Ao", "Af", "Ag"];
+        let lineages: HashMap<String, Lineage> = vec![
+            ("GCF_1.1", lineage(&[&genus[..], &["Ag x"]].concat())),
+            ("GCF_2.1", lineage(&[&genus[..], &["Ag y"]].concat())),
+            ("GCF_3.1", lineage(&["Bacteria"])),
+        ]
+        .into_iter()
+        .map(|(ident, lineage)| (ident.to_string(), lineage))
+        .collect();
+        let unversioned = lineages
+            .iter()
+            .map(|(ident, lineage)| (strip_version(ident).to_string(), lineage.clone()))
+            .collect();
+        Taxonomy {
+            lineages,
+            unversioned,
+        }
+    }
+
+    fn row(query: Option<&str>, name: &str, fraction: f64, bp: usize) -> GatherCsvRow {
+        GatherCsvRow {
+            query: query.map(String::from),
+            name: name.into(),
+            f_unique_weighted: fraction,
+            unique_intersect_bp: bp,
+        }
+    }
+
+    fn summary() -> QuerySummary {
+        let rows = vec![
+            row(None, "GCF_1.1 Ag x strain", 0.3, 3000),
+            row(None, "RS_GCF_2.1", 0.2, 2000),
+            row(None, "GCF_3", 0.1, 1000),
+            row(None, "unknown", 0.1, 500),
+        ];
+        QuerySummary::new("sample".into(), &rows, &taxonomy())
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn rank_rollup() {
+        let summary = summary();
+
+        // Matches are added up at every rank they have a name for
+        let superkingdom = &summary.ranks[0];
+        assert_eq!(superkingdom.len(), 1);
+        assert_eq!(superkingdom[0].0, vec!["Bacteria"]);
+        assert_close(superkingdom[0].1, 0.6);
+        assert_eq!(superkingdom[0].2, 6000);
+
+        let genus = &summary.ranks[5];
+        assert_eq!(genus.len(), 1);
+        assert_close(genus[0].1, 0.5);
+        assert_eq!(genus[0].2, 5000);
+
+        let species = &summary.ranks[6];
+        assert_eq!(species.len(), 2);
+        assert_eq!(species[0].0[6], "Ag x");
+        assert_close(species[0].1, 0.3);
+        assert_eq!(species[1].0[6], "Ag y");
+        assert_eq!(species[1].2, 2000);
+    }
+
+    #[test]
+    fn unclassified_remainder() {
+        let summary = summary();
+
+        // Matches without a lineage and the rest of the query
+        assert_close(summary.unclassified(0), 0.4);
+        // GCF_3.1 has no name below superkingdom
+        assert_close(summary.unclassified(1), 0.5);
+        assert_close(summary.unclassified(6), 0.5);
+
+        let empty = QuerySummary::new("empty".into(), &[], &taxonomy());
+        assert_close(empty.unclassified(0), 1.);
+    }
+
+    #[test]
+    fn kraken_bp_direct() {
+        let mut out = vec![];
+        summary().write_kraken(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                "40.00\t0\t0\tU\t\tunclassified",
+                // GCF_3.1 is only assigned at superkingdom
+                "60.00\t6000\t1000\tD\t\tBacteria",
+                "50.00\t5000\t0\tP\t\t  A",
+                "50.00\t5000\t0\tC\t\t    Ac",
+                "50.00\t5000\t0\tO\t\t      Ao",
+                "50.00\t5000\t0\tF\t\t        Af",
+                "50.00\t5000\t0\tG\t\t          Ag",
+                "30.00\t3000\t3000\tS\t\t            Ag x",
+                "20.00\t2000\t2000\tS\t\t            Ag y",
+            ]
+        );
+    }
+
+    #[test]
+    fn group_by_full_query() {
+        let rows = vec![
+            row(Some("a/sample.sig"), "GCF_1.1", 0.3, 3000),
+            row(Some("b/sample.sig"), "GCF_2.1", 0.2, 2000),
+            row(Some("a/sample.sig"), "GCF_3.1", 0.1, 1000),
+        ];
+        let groups = group_by_query(Path::new("results.csv"), rows);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].source, PathBuf::from("a/sample.sig"));
+        assert_eq!(groups[0].rows.len(), 2);
+        assert_eq!(groups[1].source, PathBuf::from("b/sample.sig"));
+        assert!(groups.iter().all(|g| g.name == "sample.sig"));
+
+        let groups = group_by_query(Path::new("out/sample.sig.csv"), vec![]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].name, "sample.sig");
+        assert_eq!(groups[0].source, PathBuf::from("out/sample.sig.csv"));
+    }
+}
@@ -0,0 +1,52 @@
+//! Containment-based ANI estimates from Scaled MinHash sketches.
+//!
+//! If a fraction `C` of the k-mers in A is present in B, and mutations are
+//! independent, the average nucleotide identity between them is estimated as
+//! `C^(1/k)`. The confidence interval comes from a Wilson score interval for
+//! `C`, treating each of the `n` hashes in the sketch as an independent trial,
+//! so smaller sketches (fewer hashes, larger scaled) give wider intervals.
+//!
+//! For protein, dayhoff and hp sketches `k` is the k-mer size in amino acids,
+//! and the estimate is an amino acid identity (AAI).
+
+use getset::CopyGetters;
+use serde::{Deserialize, Serialize};
+
+/// z-score for a 95% confidence interval
+const Z_95: f64 = 1.959_964;
+
+#[derive(CopyGetters, Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct AniEstimate {
+    #[getset(get_copy = "pub")]
+    ani: f64,
+
+    #[getset(get_copy = "pub")]
+    low: f64,
+
+    #[getset(get_copy = "pub")]
+    high: f64,
+}
+
+impl AniEstimate {
+    /// Estimate ANI from `shared` hashes out of the `n_hashes` in a sketch
+    /// built with `ksize` (in amino acids for protein sketches).
+    pub fn from_containment(shared: usize, n_hashes: usize, ksize: u32) -> AniEstimate {
+        if n_hashes == 0 || ksize == 0 {
+            return AniEstimate::default();
+        }
+
+        let n = n_hashes as f64;
+        let c = (shared as f64 / n).min(1.);
+
+        let z2 = Z_95 * Z_95;
+        let center = (c + z2 / (2. * n)) / (1. + z2 / n);
+        let margin = Z_95 / (1. + z2 / n) * (c * (1. - c) / n + z2 / (4. * n * n)).sqrt();
+
+        let to_ani = |c: f64| c.max(0.).min(1.).powf(1. / ksize as f64);
+        AniEstimate {
+            ani: to_ani(c),
+            low: to_ani(center - margin),
+            high: to_ani(center + margin),
+        }
+    }
+}
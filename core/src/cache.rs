@@ -0,0 +1,244 @@
+//! Cache for reference signatures loaded during gather, bounded by an
+//! estimate of the memory they use. The least recently used signatures are
+//! evicted first.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use getset::CopyGetters;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::revindex::DatasetID;
+
+/// Fixed cost assumed for each signature (names, filenames, allocations).
+const ENTRY_OVERHEAD: usize = 1024;
+
+type Cached = Arc<(Signature, KmerMinHash)>;
+
+struct Entry {
+    value: Cached,
+    bytes: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<DatasetID, Entry>,
+    /// Datasets by the last time they were used
+    by_use: BTreeMap<u64, DatasetID>,
+    used_bytes: usize,
+    tick: u64,
+    stats: CacheStats,
+}
+
+#[derive(CopyGetters, Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    #[getset(get_copy = "pub")]
+    hits: u64,
+
+    #[getset(get_copy = "pub")]
+    misses: u64,
+
+    #[getset(get_copy = "pub")]
+    evictions: u64,
+}
+
+pub(crate) struct SigCache {
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+}
+
+impl SigCache {
+    pub(crate) fn new(max_bytes: usize) -> SigCache {
+        SigCache {
+            max_bytes,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    pub(crate) fn get(&self, dataset_id: DatasetID) -> Option<Cached> {
+        let mut state = self.state.lock().unwrap();
+        state.tick += 1;
+        let tick = state.tick;
+
+        let state = &mut *state;
+        match state.entries.get_mut(&dataset_id) {
+            Some(entry) => {
+                state.by_use.remove(&entry.last_used);
+                state.by_use.insert(tick, dataset_id);
+                entry.last_used = tick;
+                state.stats.hits += 1;
+                Some(entry.value.clone())
+            }
+            None => {
+                state.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Add a signature, evicting the least recently used ones until it fits.
+    /// Signatures larger than the whole cache are not kept.
+    pub(crate) fn insert(&self, dataset_id: DatasetID, sig: Signature, mh: KmerMinHash) {
+        let bytes = estimated_size(&sig, &mh);
+        if bytes > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.entries.contains_key(&dataset_id) {
+            return;
+        }
+
+        while state.used_bytes + bytes > self.max_bytes {
+            let (&oldest, &evicted) = match state.by_use.iter().next() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            state.by_use.remove(&oldest);
+            if let Some(entry) = state.entries.remove(&evicted) {
+                state.used_bytes -= entry.bytes;
+            }
+            state.stats.evictions += 1;
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.by_use.insert(tick, dataset_id);
+        state.entries.insert(
+            dataset_id,
+            Entry {
+                value: Arc::new((sig, mh)),
+                bytes,
+                last_used: tick,
+            },
+        );
+        state.used_bytes += bytes;
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.by_use.clear();
+        state.used_bytes = 0;
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// Approximate memory used by a signature and its selected sketch, counting
+/// 8 bytes per hash (and 8 more per abundance).
+fn estimated_size(sig: &Signature, mh: &KmerMinHash) -> usize {
+    let sketch_bytes = |mh: &KmerMinHash| {
+        let per_hash = if mh.track_abundance() { 16 } else { 8 };
+        mh.size() * per_hash
+    };
+
+    let sig_bytes: usize = sig
+        .sketches()
+        .iter()
+        .map(|sketch| match sketch {
+            Sketch::MinHash(mh) => sketch_bytes(mh),
+            _ => 0,
+        })
+        .sum();
+    sig_bytes + sketch_bytes(mh) + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use sourmash::sketch::minhash::max_hash_for_scaled;
+
+    use super::*;
+
+    /// A signature with no sketches, selecting a sketch with `n_hashes`
+    /// hashes, using `ENTRY_OVERHEAD + 8 * n_hashes` bytes.
+    fn entry(n_hashes: u64) -> (Signature, KmerMinHash) {
+        let mut mh = KmerMinHash::builder()
+            .num(0u32)
+            .ksize(31)
+            .max_hash(max_hash_for_scaled(1000))
+            .build();
+        mh.add_many(&(1..=n_hashes).collect::<Vec<_>>()).unwrap();
+        (Signature::default(), mh)
+    }
+
+    fn insert(cache: &SigCache, dataset_id: DatasetID, n_hashes: u64) {
+        let (sig, mh) = entry(n_hashes);
+        cache.insert(dataset_id, sig, mh);
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = SigCache::new(2 * ENTRY_OVERHEAD + 100);
+        insert(&cache, 0, 0);
+        insert(&cache, 1, 0);
+        assert!(cache.get(0).is_some());
+
+        insert(&cache, 2, 0);
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(0).is_some());
+        assert!(cache.get(2).is_some());
+
+        // 0 was used before 2, so it goes first now
+        insert(&cache, 3, 0);
+        assert!(cache.get(0).is_none());
+        assert!(cache.get(2).is_some());
+        assert!(cache.get(3).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.evictions(), 2);
+        assert_eq!(stats.hits(), 5);
+        assert_eq!(stats.misses(), 2);
+    }
+
+    #[test]
+    fn evict_until_larger_entry_fits() {
+        let cache = SigCache::new(3 * ENTRY_OVERHEAD);
+        insert(&cache, 0, 0);
+        insert(&cache, 1, 0);
+        insert(&cache, 2, 0);
+
+        // Needs the space of two entries
+        insert(&cache, 3, 100);
+        assert!(cache.get(0).is_none());
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(2).is_some());
+        assert!(cache.get(3).is_some());
+        assert_eq!(cache.stats().evictions(), 2);
+    }
+
+    #[test]
+    fn skip_oversize_entries() {
+        let cache = SigCache::new(2 * ENTRY_OVERHEAD);
+        insert(&cache, 0, 0);
+
+        insert(&cache, 1, 200);
+        assert!(cache.get(1).is_none());
+        // Nothing is evicted for an entry that can't be kept
+        assert!(cache.get(0).is_some());
+        assert_eq!(cache.stats().evictions(), 0);
+    }
+
+    #[test]
+    fn clear_entries() {
+        let cache = SigCache::new(2 * ENTRY_OVERHEAD);
+        insert(&cache, 0, 0);
+        insert(&cache, 1, 0);
+
+        cache.clear();
+        assert!(cache.get(0).is_none());
+        assert!(cache.get(1).is_none());
+
+        // The freed space is available again
+        insert(&cache, 2, 0);
+        insert(&cache, 3, 0);
+        assert!(cache.get(2).is_some());
+        assert!(cache.get(3).is_some());
+        assert_eq!(cache.stats().evictions(), 0);
+    }
+}
@@ -0,0 +1,408 @@
+//! Listing and loading signatures from the inputs accepted by greyhound:
+//! files with one signature path per line, directories, sourmash zip
+//! collections and sourmash manifest CSVs. Queries can also be FASTA/FASTQ
+//! files, which are sketched when loaded.
+//!
+//! Signatures inside a zip collection are referred to by the path of the
+//! zip file followed by their location inside it (for example
+//! `refs.zip/signatures/<md5>.sig.gz`), so they can be stored in an index
+//! like any other signature path.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde::Deserialize;
+use sourmash::signature::Signature;
+use sourmash::sketch::Sketch;
+
+use crate::multi::TemplateEntry;
+use crate::Error;
+
+const ZIP_MANIFEST: &str = "SOURMASH-MANIFEST.csv";
+const MANIFEST_HEADER: &str = "# SOURMASH-MANIFEST-VERSION";
+
+const SEQUENCE_EXTENSIONS: &[&str] = &["fa", "fasta", "fna", "fq", "fastq"];
+const COMPRESSION_EXTENSIONS: &[&str] = &["gz", "bz2", "xz"];
+
+/// A row from a sourmash manifest. Only the columns needed for selecting
+/// sketches are read.
+#[derive(Deserialize, Debug)]
+struct ManifestRow {
+    internal_location: String,
+    ksize: u32,
+    moltype: String,
+    num: u32,
+}
+
+impl ManifestRow {
+    /// Check if this sketch has the same ksize and molecule type as
+    /// `template`. Sketches with a different scaled are kept, so they can be
+    /// downsampled (or reported as too coarse) when loaded.
+    fn compatible(&self, template: &TemplateEntry) -> bool {
+        // Manifests list protein, dayhoff and hp ksizes in amino acids,
+        // but sketches (and so templates) store them in nucleotides
+        let ksize = if self.moltype.eq_ignore_ascii_case("DNA") {
+            self.ksize
+        } else {
+            self.ksize * 3
+        };
+        ksize == template.ksize()
+            && self.moltype.eq_ignore_ascii_case(template.moltype())
+            && self.num == 0
+    }
+}
+
+/// List the signatures in `input`.
+///
+/// `input` can be a directory (searched recursively for `.sig`, `.sig.gz`
+/// and `.zip` files), a sourmash zip collection, a sourmash manifest CSV, a
+/// single signature file or a file with one path per line. If `template` is
+/// provided, manifests are used to skip signatures without a compatible
+/// sketch before parsing them.
+pub fn list_signatures<P: AsRef<Path>>(
+    input: P,
+    template: Option<&Sketch>,
+) -> Result<Vec<PathBuf>, Error> {
+    list_inputs(input.as_ref(), template, false)
+}
+
+/// List queries in `input`, like `list_signatures`. FASTA/FASTQ files are
+/// listed too, either given directly or found in directories.
+pub fn list_queries<P: AsRef<Path>>(
+    input: P,
+    template: Option<&Sketch>,
+) -> Result<Vec<PathBuf>, Error> {
+    list_inputs(input.as_ref(), template, true)
+}
+
+fn list_inputs(
+    input: &Path,
+    template: Option<&Sketch>,
+    sequences: bool,
+) -> Result<Vec<PathBuf>, Error> {
+    let template = match template {
+        Some(template) => Some(TemplateEntry::from_template(template, "")?),
+        None => None,
+    };
+
+    let paths = if input.is_dir() {
+        let mut paths = vec![];
+        list_dir(input, template.as_ref(), sequences, &mut paths)?;
+        paths
+    } else if has_extension(input, "zip") {
+        list_zip(input, template.as_ref())?
+    } else if is_single_signature(input)? || (sequences && is_sequence_file(input)?) {
+        vec![input.to_path_buf()]
+    } else if is_manifest(input)? {
+        let base = input.parent().unwrap_or_else(|| Path::new(""));
+        let rdr = BufReader::new(File::open(input)?);
+        read_manifest(rdr, template.as_ref())?
+            .into_iter()
+            .map(|location| base.join(location))
+            .collect()
+    } else {
+        read_path_list(input)?
+    };
+
+    info!("Found {} signatures in {:?}", paths.len(), input);
+    Ok(paths)
+}
+
+/// Load signatures from a path returned by `list_signatures`.
+pub fn load_signatures<P: AsRef<Path>>(path: P) -> Result<Vec<Signature>, Error> {
+    let path = path.as_ref();
+    match split_zip_location(path) {
+        Some((zip_path, location)) => {
+            let mut archive = zip::ZipArchive::new(BufReader::new(File::open(zip_path)?))?;
+            let mut entry = archive.by_name(&location)?;
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+
+            let (rdr, _) = niffler::get_reader(Box::new(&data[..]))?;
+            Ok(Signature::from_reader(rdr)?)
+        }
+        None => Ok(Signature::from_path(path)?),
+    }
+}
+
+/// Check if `path` refers to a signature inside a zip collection.
+pub fn is_zip_member<P: AsRef<Path>>(path: P) -> bool {
+    split_zip_location(path.as_ref()).is_some()
+}
+
+fn split_zip_location(path: &Path) -> Option<(&Path, String)> {
+    if path.exists() {
+        return None;
+    }
+
+    let zip_path = path
+        .ancestors()
+        .skip(1)
+        .find(|ancestor| has_extension(ancestor, "zip") && ancestor.is_file())?;
+    let location = path.strip_prefix(zip_path).ok()?;
+    // Zip entries always use `/` as separator
+    let location = location
+        .iter()
+        .map(|component| component.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    Some((zip_path, location))
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case(extension))
+}
+
+fn is_signature_file(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".sig") || name.ends_with(".sig.gz")
+}
+
+/// Check for FASTA/FASTQ extensions, optionally followed by a compression
+/// extension.
+fn is_sequence_name(path: &Path) -> bool {
+    let path = match path.extension() {
+        Some(ext)
+            if COMPRESSION_EXTENSIONS
+                .iter()
+                .any(|c| ext.eq_ignore_ascii_case(c)) =>
+        {
+            path.with_extension("")
+        }
+        _ => path.to_path_buf(),
+    };
+    SEQUENCE_EXTENSIONS
+        .iter()
+        .any(|ext| has_extension(&path, ext))
+}
+
+/// Check if a file (optionally compressed) contains FASTA/FASTQ records
+/// instead of signatures, by peeking at the first non-whitespace byte.
+pub fn is_sequence_file<P: AsRef<Path>>(path: P) -> Result<bool, Error> {
+    let (rdr, _) = niffler::from_path(path)?;
+    for byte in rdr.bytes().take(1024) {
+        match byte? {
+            b'>' | b'@' => return Ok(true),
+            b if b.is_ascii_whitespace() => continue,
+            _ => return Ok(false),
+        }
+    }
+    Ok(false)
+}
+
+fn list_dir(
+    dir: &Path,
+    template: Option<&TemplateEntry>,
+    sequences: bool,
+    paths: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            list_dir(&path, template, sequences, paths)?;
+        } else if has_extension(&path, "zip") {
+            paths.extend(list_zip(&path, template)?);
+        } else if is_signature_file(&path) || (sequences && is_sequence_name(&path)) {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn list_zip(zip_path: &Path, template: Option<&TemplateEntry>) -> Result<Vec<PathBuf>, Error> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(File::open(zip_path)?))?;
+
+    let manifest = match archive.by_name(ZIP_MANIFEST) {
+        Ok(manifest) => Some(read_manifest(BufReader::new(manifest), template)?),
+        Err(zip::result::ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let locations = match manifest {
+        Some(locations) => locations,
+        None => {
+            // No manifest, so all signatures need to be parsed later
+            let mut locations = vec![];
+            for i in 0..archive.len() {
+                let name = archive.by_index(i)?.name().to_string();
+                if is_signature_file(Path::new(&name)) {
+                    locations.push(name);
+                }
+            }
+            locations
+        }
+    };
+
+    Ok(locations
+        .into_iter()
+        .map(|location| zip_path.join(location))
+        .collect())
+}
+
+/// Check if `path` is a signature file, by extension or by content:
+/// signatures are JSON, while path lists and manifests are not.
+fn is_single_signature(path: &Path) -> Result<bool, Error> {
+    if is_signature_file(path) {
+        return Ok(true);
+    }
+
+    let (rdr, _) = niffler::from_path(path)?;
+    for byte in BufReader::new(rdr).bytes() {
+        let byte = byte?;
+        if !byte.is_ascii_whitespace() {
+            return Ok(byte == b'[' || byte == b'{');
+        }
+    }
+    Ok(false)
+}
+
+fn is_manifest(path: &Path) -> Result<bool, Error> {
+    let mut first_line = String::new();
+    BufReader::new(File::open(path)?).read_line(&mut first_line)?;
+    Ok(first_line.starts_with(MANIFEST_HEADER))
+}
+
+/// Read the locations of signatures with a sketch compatible with
+/// `template` (or all of them) from a manifest.
+fn read_manifest<R: Read>(rdr: R, template: Option<&TemplateEntry>) -> Result<Vec<String>, Error> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .from_reader(rdr);
+
+    // A signature can have several sketches, and so several rows
+    let mut seen = HashSet::new();
+    let mut locations = vec![];
+    let mut skipped = 0;
+    for row in rdr.deserialize() {
+        let row: ManifestRow = row?;
+        if template.map_or(true, |t| row.compatible(t)) {
+            if seen.insert(row.internal_location.clone()) {
+                locations.push(row.internal_location);
+            }
+        } else {
+            skipped += 1;
+        }
+    }
+
+    if skipped > 0 {
+        info!(
+            "Skipped {} incompatible sketches listed in manifest",
+            skipped
+        );
+    }
+    Ok(locations)
+}
+
+fn read_path_list(paths_file: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = vec![];
+    for line in BufReader::new(File::open(paths_file)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            paths.push(PathBuf::from(line));
+        }
+    }
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use sourmash::encodings::HashFunctions;
+    use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+
+    fn entry(ksize: u32, moltype: HashFunctions) -> TemplateEntry {
+        let mh = KmerMinHash::builder()
+            .num(0u32)
+            .ksize(ksize)
+            .hash_function(moltype)
+            .max_hash(max_hash_for_scaled(1000))
+            .build();
+        TemplateEntry::from_template(&Sketch::MinHash(mh), "").unwrap()
+    }
+
+    fn row(ksize: u32, moltype: &str) -> ManifestRow {
+        ManifestRow {
+            internal_location: "signatures/a.sig.gz".into(),
+            ksize,
+            moltype: moltype.into(),
+            num: 0,
+        }
+    }
+
+    #[test]
+    fn manifest_ksize_by_moltype() {
+        let dna = entry(31, HashFunctions::murmur64_DNA);
+        assert!(row(31, "DNA").compatible(&dna));
+        assert!(!row(21, "DNA").compatible(&dna));
+
+        // Protein ksizes are in amino acids in manifests
+        let protein = entry(30, HashFunctions::murmur64_protein);
+        assert!(row(10, "protein").compatible(&protein));
+        assert!(!row(30, "protein").compatible(&protein));
+        assert!(!row(10, "dayhoff").compatible(&protein));
+    }
+
+    #[test]
+    fn single_signature_by_content() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let sig = dir.path().join("query");
+        File::create(&sig)
+            .unwrap()
+            .write_all(b"  [{\"signatures\": []}]")
+            .unwrap();
+        assert!(is_single_signature(&sig).unwrap());
+
+        let list = dir.path().join("paths.txt");
+        File::create(&list)
+            .unwrap()
+            .write_all(b"a.sig\nb.sig\n")
+            .unwrap();
+        assert!(!is_single_signature(&list).unwrap());
+
+        assert!(is_single_signature(Path::new("missing.sig.gz")).unwrap());
+    }
+
+    #[test]
+    fn list_sequence_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            File::create(&path).unwrap().write_all(data).unwrap();
+            path
+        };
+        let fasta = write("a.fa", b">read1\nACGT\n>read2\nTTGA\n");
+        write("b.fastq.gz", b"");
+        write("c.sig", b"[]");
+        write("notes.txt", b"");
+
+        // A single FASTA file is a query, not a list of paths
+        assert_eq!(list_queries(&fasta, None).unwrap(), vec![fasta.clone()]);
+
+        let names = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(
+            names(list_queries(dir.path(), None).unwrap()),
+            vec!["a.fa", "b.fastq.gz", "c.sig"]
+        );
+        assert_eq!(
+            names(list_signatures(dir.path(), None).unwrap()),
+            vec!["c.sig"]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Inverted index (hash -> datasets) used by the greyhound CLI and server
+//! for running gather and search over large collections of Scaled MinHash
+//! signatures.
+
+mod ani;
+mod cache;
+mod collection;
+mod multi;
+mod picklist;
+mod postings;
+mod revindex;
+mod stats;
+mod storage;
+
+pub use crate::ani::AniEstimate;
+pub use crate::cache::CacheStats;
+pub use crate::collection::{
+    is_sequence_file, is_zip_member, list_queries, list_signatures, load_signatures,
+};
+pub use crate::multi::{select_template, MultiIndex, TemplateEntry, MANIFEST_FILE};
+pub use crate::picklist::{PickStyle, Picklist};
+pub use crate::revindex::{
+    describe_template, select_minhash, AbundStats, DatasetID, DatasetInfo, GatherResult, RevIndex,
+    SearchResult, SigCounter,
+};
+pub use crate::stats::{HistogramBin, IndexStats, SharedHash};
+pub use crate::storage::{write_atomic, IndexFormat};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid index file ({0})")]
+    InvalidIndex(String),
+
+    #[error("Unsupported index format version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("No compatible sketch found in {0}")]
+    NoCompatibleSketch(String),
+
+    #[error("Sketch has scaled={scaled}, coarser than the index scaled={template_scaled}")]
+    CoarserSketch { scaled: u64, template_scaled: u64 },
+
+    #[error("Incompatible index template: {0}")]
+    IncompatibleTemplate(String),
+
+    #[error("No index matching the requested template ({0})")]
+    NoMatchingTemplate(String),
+
+    #[error("Several indices match the requested template: {0}")]
+    AmbiguousTemplate(String),
+
+    #[error("Can't load signatures from {path}: {source}")]
+    InvalidSignature {
+        path: String,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[error("Invalid picklist ({0})")]
+    InvalidPicklist(String),
+
+    #[error("Memory-mapped indices can't be modified")]
+    ReadOnlyIndex,
+
+    #[error(transparent)]
+    Sourmash(#[from] sourmash::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Niffler(#[from] niffler::Error),
+
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,213 @@
+//! Containers holding one index per template (ksize, molecule type and
+//! scaled), so a single path can be used for several ksizes and moltypes.
+//!
+//! A container is a directory with a `greyhound.json` manifest listing the
+//! templates and the index file (binary or JSON) for each one.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use sourmash::signature::SigsTrait;
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::revindex::RevIndex;
+use crate::storage::{write_atomic, IndexFormat};
+use crate::Error;
+
+pub const MANIFEST_FILE: &str = "greyhound.json";
+
+/// Template parameters for one index in a container.
+#[derive(CopyGetters, Getters, Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateEntry {
+    #[getset(get_copy = "pub")]
+    ksize: u32,
+
+    /// Molecule type, as reported by sourmash (DNA, protein, dayhoff or hp)
+    #[getset(get = "pub")]
+    moltype: String,
+
+    #[getset(get_copy = "pub")]
+    scaled: u64,
+
+    /// Index path, relative to the container directory
+    #[getset(get = "pub")]
+    path: PathBuf,
+}
+
+impl TemplateEntry {
+    pub fn from_template<P: Into<PathBuf>>(template: &Sketch, path: P) -> Result<Self, Error> {
+        match template {
+            Sketch::MinHash(mh) => Ok(TemplateEntry {
+                ksize: mh.ksize() as u32,
+                moltype: mh.hash_function().to_string(),
+                scaled: mh.scaled(),
+                path: path.into(),
+            }),
+            _ => Err(Error::IncompatibleTemplate(
+                "only MinHash templates are supported".into(),
+            )),
+        }
+    }
+
+    /// Check if this template has the provided parameters.
+    /// Parameters set to `None` match any value.
+    pub fn matches(&self, ksize: Option<u32>, moltype: Option<&str>, scaled: Option<u64>) -> bool {
+        ksize.map_or(true, |k| k == self.ksize)
+            && moltype.map_or(true, |m| m.eq_ignore_ascii_case(&self.moltype))
+            && scaled.map_or(true, |s| s == self.scaled)
+    }
+
+    fn same_template(&self, other: &TemplateEntry) -> bool {
+        self.matches(Some(other.ksize), Some(&other.moltype), Some(other.scaled))
+    }
+}
+
+impl std::fmt::Display for TemplateEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "k={} moltype={} scaled={}",
+            self.ksize, self.moltype, self.scaled
+        )
+    }
+}
+
+/// Find the only template matching the provided parameters.
+/// Parameters set to `None` match any template.
+pub fn select_template(
+    entries: &[TemplateEntry],
+    ksize: Option<u32>,
+    moltype: Option<&str>,
+    scaled: Option<u64>,
+) -> Result<usize, Error> {
+    let found: Vec<usize> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.matches(ksize, moltype, scaled))
+        .map(|(i, _)| i)
+        .collect();
+
+    match found.len() {
+        1 => Ok(found[0]),
+        0 => Err(Error::NoMatchingTemplate(format!(
+            "available: {}",
+            describe_entries(entries)
+        ))),
+        _ => Err(Error::AmbiguousTemplate(describe_entries(
+            found.iter().map(|i| &entries[*i]),
+        ))),
+    }
+}
+
+fn describe_entries<'a, I: IntoIterator<Item = &'a TemplateEntry>>(entries: I) -> String {
+    entries
+        .into_iter()
+        .map(|entry| entry.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MultiIndex {
+    #[serde(skip)]
+    root: PathBuf,
+    indices: Vec<TemplateEntry>,
+}
+
+impl MultiIndex {
+    /// Check if `path` is a container directory, instead of a single index.
+    pub fn is_multi<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref().join(MANIFEST_FILE).is_file()
+    }
+
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<MultiIndex, Error> {
+        let manifest = File::open(root.as_ref().join(MANIFEST_FILE))?;
+        let mut multi: MultiIndex = serde_json::from_reader(manifest)?;
+        multi.root = root.as_ref().into();
+        Ok(multi)
+    }
+
+    /// Open a container, or create an empty one if it doesn't exist yet.
+    pub fn create<P: AsRef<Path>>(root: P) -> Result<MultiIndex, Error> {
+        if Self::is_multi(&root) {
+            return Self::open(root);
+        }
+
+        std::fs::create_dir_all(&root)?;
+        let multi = MultiIndex {
+            root: root.as_ref().into(),
+            indices: vec![],
+        };
+        multi.save_manifest()?;
+        Ok(multi)
+    }
+
+    pub fn entries(&self) -> &[TemplateEntry] {
+        &self.indices
+    }
+
+    pub fn select(
+        &self,
+        ksize: Option<u32>,
+        moltype: Option<&str>,
+        scaled: Option<u64>,
+    ) -> Result<&TemplateEntry, Error> {
+        select_template(&self.indices, ksize, moltype, scaled).map(|i| &self.indices[i])
+    }
+
+    /// Find the index built with the same parameters as `template`, if any.
+    pub fn find(&self, template: &Sketch) -> Option<&TemplateEntry> {
+        let wanted = TemplateEntry::from_template(template, PathBuf::new()).ok()?;
+        self.indices
+            .iter()
+            .find(|entry| entry.same_template(&wanted))
+    }
+
+    pub fn index_path(&self, entry: &TemplateEntry) -> PathBuf {
+        self.root.join(&entry.path)
+    }
+
+    pub fn load(
+        &self,
+        entry: &TemplateEntry,
+        queries: Option<&[KmerMinHash]>,
+    ) -> Result<RevIndex, Error> {
+        RevIndex::load(self.index_path(entry), queries)
+    }
+
+    pub fn load_mmap(&self, entry: &TemplateEntry) -> Result<RevIndex, Error> {
+        RevIndex::load_mmap(self.index_path(entry))
+    }
+
+    /// Save `revindex` in the container, replacing the index with the same
+    /// template if there is one.
+    pub fn insert(&mut self, revindex: &RevIndex, format: IndexFormat) -> Result<(), Error> {
+        let template = &revindex.template;
+        let mut entry = TemplateEntry::from_template(template, PathBuf::new())?;
+        entry.path = format!(
+            "k{}.{}.scaled{}.idx",
+            entry.ksize,
+            entry.moltype.to_lowercase(),
+            entry.scaled
+        )
+        .into();
+
+        // Saving is atomic, so a failed save doesn't leave a truncated
+        // index listed in the manifest.
+        revindex.save(self.index_path(&entry), format)?;
+
+        self.indices.retain(|e| !e.same_template(&entry));
+        self.indices.push(entry);
+        self.save_manifest()
+    }
+
+    fn save_manifest(&self) -> Result<(), Error> {
+        write_atomic(&self.root.join(MANIFEST_FILE), |out| {
+            serde_json::to_writer_pretty(out, &self)?;
+            Ok(())
+        })
+    }
+}
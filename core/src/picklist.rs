@@ -0,0 +1,136 @@
+//! Picklists for restricting results to a subset of the indexed datasets,
+//! compatible with the `file.csv:column:type` format used by sourmash.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::revindex::DatasetInfo;
+use crate::Error;
+
+/// How picklist values are compared with datasets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickStyle {
+    /// Full signature name
+    Name,
+    /// First word of the signature name (usually an accession)
+    Ident,
+    /// Identifier without the version (the part before the first `.`)
+    IdentPrefix,
+    /// Full md5
+    Md5,
+    /// First 8 characters of the md5
+    Md5Prefix8,
+}
+
+impl PickStyle {
+    pub const VARIANTS: &'static [&'static str] =
+        &["name", "ident", "identprefix", "md5", "md5prefix8"];
+
+    /// Extract the value to compare from a dataset (or picklist value).
+    fn key<'a>(&self, value: &'a str) -> &'a str {
+        match self {
+            PickStyle::Name | PickStyle::Md5 => value,
+            PickStyle::Ident => value.split_whitespace().next().unwrap_or(""),
+            PickStyle::IdentPrefix => {
+                let ident = value.split_whitespace().next().unwrap_or("");
+                ident.split('.').next().unwrap_or("")
+            }
+            PickStyle::Md5Prefix8 => value.get(..8).unwrap_or(value),
+        }
+    }
+}
+
+impl FromStr for PickStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "name" => Ok(PickStyle::Name),
+            "ident" => Ok(PickStyle::Ident),
+            "identprefix" => Ok(PickStyle::IdentPrefix),
+            "md5" => Ok(PickStyle::Md5),
+            "md5prefix8" | "md5short" => Ok(PickStyle::Md5Prefix8),
+            _ => Err(format!(
+                "unknown picklist type {}, expected one of {}",
+                s,
+                Self::VARIANTS.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Picklist {
+    path: PathBuf,
+    style: PickStyle,
+    values: HashSet<String>,
+    exclude: bool,
+}
+
+impl Picklist {
+    /// Load a picklist from a `file.csv:column:type` specification.
+    ///
+    /// If `exclude` is set, datasets matching the picklist are removed
+    /// instead of kept.
+    pub fn from_spec(spec: &str, exclude: bool) -> Result<Picklist, Error> {
+        // Split from the end, so paths can contain `:`
+        let mut parts = spec.rsplitn(3, ':');
+        let (style, column, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(style), Some(column), Some(path)) => (style, column, path),
+            _ => {
+                return Err(Error::InvalidPicklist(format!(
+                    "expected file.csv:column:type, got {}",
+                    spec
+                )))
+            }
+        };
+        let style: PickStyle = style.parse().map_err(Error::InvalidPicklist)?;
+
+        let mut rdr = csv::Reader::from_path(path)?;
+        let idx = rdr
+            .headers()?
+            .iter()
+            .position(|header| header == column)
+            .ok_or_else(|| {
+                Error::InvalidPicklist(format!("column {} not found in {}", column, path))
+            })?;
+
+        let mut values = HashSet::new();
+        for record in rdr.records() {
+            let record = record?;
+            if let Some(value) = record.get(idx) {
+                values.insert(style.key(value).to_string());
+            }
+        }
+
+        Ok(Picklist {
+            path: path.into(),
+            style,
+            values,
+            exclude,
+        })
+    }
+
+    /// Check if a dataset passes this picklist.
+    pub fn matches(&self, info: &DatasetInfo) -> bool {
+        let value = match self.style {
+            PickStyle::Md5 | PickStyle::Md5Prefix8 => &info.md5,
+            _ => &info.name,
+        };
+        self.values.contains(self.style.key(value)) != self.exclude
+    }
+}
+
+impl std::fmt::Display for Picklist {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}picklist {:?} ({:?}, {} values)",
+            if self.exclude { "exclude " } else { "" },
+            self.path,
+            self.style,
+            self.values.len()
+        )
+    }
+}
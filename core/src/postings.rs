@@ -0,0 +1,282 @@
+//! Hash -> datasets postings, either loaded in memory or looked up lazily
+//! from a memory-mapped binary index.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::hash::BuildHasherDefault;
+use std::io;
+use std::path::Path;
+
+use log::error;
+use memmap2::Mmap;
+use nohash_hasher::NoHashHasher;
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::revindex::DatasetID;
+use crate::storage::{self, Header, BLOCK_SIZE};
+use crate::Error;
+
+pub(crate) type HashToIdx = HashMap<u64, Vec<DatasetID>, BuildHasherDefault<NoHashHasher<u64>>>;
+
+pub(crate) enum Postings {
+    Memory(HashToIdx),
+    Mapped(MappedPostings),
+}
+
+impl Postings {
+    pub(crate) fn get(&self, hash: u64) -> Option<Cow<'_, [DatasetID]>> {
+        match self {
+            Postings::Memory(map) => map.get(&hash).map(|d| Cow::Borrowed(d.as_slice())),
+            Postings::Mapped(mapped) => mapped.get(hash).map(Cow::Owned),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Postings::Memory(map) => map.len(),
+            Postings::Mapped(mapped) => mapped.n_hashes,
+        }
+    }
+
+    /// Call `f` for every hash and its datasets, in no particular order.
+    pub(crate) fn try_for_each<F: FnMut(u64, &[DatasetID])>(&self, mut f: F) -> Result<(), Error> {
+        match self {
+            Postings::Memory(map) => map.iter().for_each(|(hash, d)| f(*hash, d)),
+            Postings::Mapped(mapped) => {
+                for posting in mapped.iter() {
+                    let (hash, d) = posting?;
+                    f(hash, &d);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All postings, sorted by hash.
+    pub(crate) fn sorted(&self) -> Result<Vec<(u64, Cow<'_, [DatasetID]>)>, Error> {
+        match self {
+            Postings::Memory(map) => {
+                let mut postings: Vec<_> = map
+                    .iter()
+                    .map(|(hash, d)| (*hash, Cow::Borrowed(d.as_slice())))
+                    .collect();
+                postings.sort_unstable_by_key(|(hash, _)| *hash);
+                Ok(postings)
+            }
+            Postings::Mapped(mapped) => mapped
+                .iter()
+                .map(|posting| {
+                    let (hash, d) = posting?;
+                    Ok((hash, Cow::Owned(d)))
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for Postings {
+    fn default() -> Self {
+        Postings::Memory(HashToIdx::default())
+    }
+}
+
+impl Serialize for Postings {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Postings::Memory(map) => map.serialize(serializer),
+            Postings::Mapped(mapped) => {
+                let mut map = serializer.serialize_map(Some(mapped.n_hashes))?;
+                for posting in mapped.iter() {
+                    let (hash, datasets) = posting.map_err(S::Error::custom)?;
+                    map.serialize_entry(&hash, &datasets)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Postings {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashToIdx::deserialize(deserializer).map(Postings::Memory)
+    }
+}
+
+/// Read-only postings backed by a memory-mapped binary index.
+///
+/// Only the block index is consulted on lookup, and a single block is
+/// scanned per hash. Pages are shared between processes mapping the same
+/// file.
+pub(crate) struct MappedPostings {
+    mmap: Mmap,
+    n_hashes: usize,
+    n_blocks: usize,
+    /// Where the postings end and the block index starts
+    index_offset: usize,
+    block_index_start: usize,
+}
+
+impl MappedPostings {
+    /// Map a binary index, checking that the block index is consistent with
+    /// the header and points inside the postings section, so lookups never
+    /// read out of bounds.
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<(Header, MappedPostings), Error> {
+        let file = File::open(path)?;
+        // Safety: the index is opened read-only, and is not expected to be
+        // modified while in use.
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        let mut rdr = &mmap[..];
+        let header = storage::read_header(&mut rdr)?;
+        let postings_start = mmap.len() - rdr.len();
+
+        let invalid = |reason: &str| Error::InvalidIndex(reason.into());
+
+        let len = mmap.len();
+        if len < postings_start + 16 {
+            return Err(invalid("index file is truncated"));
+        }
+        let footer = len - 8;
+        let index_offset = to_usize(read_u64(&mmap, footer))?;
+        if index_offset < postings_start || index_offset > footer - 8 {
+            return Err(invalid("block index out of bounds"));
+        }
+        let n_blocks = to_usize(read_u64(&mmap, index_offset))?;
+        let block_index_start = index_offset + 8;
+        if n_blocks.checked_mul(16) != Some(footer - block_index_start) {
+            return Err(invalid("block index size mismatch"));
+        }
+
+        let n_hashes = to_usize(header.n_hashes)?;
+        let expected_blocks = n_hashes / BLOCK_SIZE + (n_hashes % BLOCK_SIZE != 0) as usize;
+        if n_blocks != expected_blocks {
+            return Err(invalid(
+                "number of blocks doesn't match the number of hashes",
+            ));
+        }
+
+        let postings = MappedPostings {
+            mmap,
+            n_hashes,
+            n_blocks,
+            index_offset,
+            block_index_start,
+        };
+
+        let mut previous = (0, postings_start);
+        for block in 0..n_blocks {
+            let (first_hash, offset) = postings.block_entry(block);
+            if offset < previous.1 || offset >= index_offset {
+                return Err(invalid("block offset out of bounds"));
+            }
+            if block > 0 && first_hash <= previous.0 {
+                return Err(invalid("block index is not sorted"));
+            }
+            previous = (first_hash, offset);
+        }
+
+        Ok((header, postings))
+    }
+
+    fn block_entry(&self, block: usize) -> (u64, usize) {
+        let start = self.block_index_start + block * 16;
+        (
+            read_u64(&self.mmap, start),
+            // Offsets were checked to fit in `open`
+            read_u64(&self.mmap, start + 8) as usize,
+        )
+    }
+
+    /// Encoded postings of a block, and the number of hashes in it.
+    fn block_data(&self, block: usize) -> (&[u8], usize) {
+        let (_, start) = self.block_entry(block);
+        let end = if block + 1 < self.n_blocks {
+            self.block_entry(block + 1).1
+        } else {
+            self.index_offset
+        };
+        let n_entries = BLOCK_SIZE.min(self.n_hashes - block * BLOCK_SIZE);
+        (&self.mmap[start..end], n_entries)
+    }
+
+    fn decode_block(&self, block: usize) -> io::Result<Vec<(u64, Vec<DatasetID>)>> {
+        let (mut rdr, n_entries) = self.block_data(block);
+        let mut entries = Vec::with_capacity(n_entries);
+        let mut previous_hash = 0;
+        for _ in 0..n_entries {
+            let (hash, datasets) = storage::read_posting(&mut rdr, previous_hash)?;
+            previous_hash = hash;
+            entries.push((hash, datasets));
+        }
+        Ok(entries)
+    }
+
+    /// Scan a block for `hash`, decoding datasets only for the matching entry.
+    fn find_in_block(&self, block: usize, hash: u64) -> io::Result<Option<Vec<DatasetID>>> {
+        let (mut rdr, n_entries) = self.block_data(block);
+        let mut previous_hash = 0;
+        for _ in 0..n_entries {
+            let current = storage::read_hash(&mut rdr, previous_hash)?;
+            if current == hash {
+                return storage::read_datasets(&mut rdr).map(Some);
+            } else if current > hash {
+                break;
+            }
+            storage::skip_datasets(&mut rdr)?;
+            previous_hash = current;
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn get(&self, hash: u64) -> Option<Vec<DatasetID>> {
+        // Find the last block starting at or before `hash`
+        let (mut low, mut high) = (0, self.n_blocks);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.block_entry(mid).0 <= hash {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return None;
+        }
+
+        match self.find_in_block(low - 1, hash) {
+            Ok(datasets) => datasets,
+            Err(e) => {
+                error!("Corrupted index block {}: {}", low - 1, e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Result<(u64, Vec<DatasetID>), Error>> + '_ {
+        (0..self.n_blocks).flat_map(move |block| {
+            let entries: Vec<Result<_, Error>> = match self.decode_block(block) {
+                Ok(entries) => entries.into_iter().map(Ok).collect(),
+                Err(e) => vec![Err(Error::InvalidIndex(format!(
+                    "corrupted block {}: {}",
+                    block, e
+                )))],
+            };
+            entries
+        })
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(buf)
+}
+
+fn to_usize(value: u64) -> Result<usize, Error> {
+    value
+        .try_into()
+        .map_err(|_| Error::InvalidIndex(format!("offset {} is too large", value)))
+}
@@ -0,0 +1,1106 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use getset::{CopyGetters, Getters};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sourmash::encodings::HashFunctions;
+use sourmash::signature::{Signature, SigsTrait};
+use sourmash::sketch::minhash::KmerMinHash;
+use sourmash::sketch::Sketch;
+
+use crate::ani::AniEstimate;
+use crate::cache::{CacheStats, SigCache};
+use crate::collection::load_signatures;
+use crate::picklist::Picklist;
+use crate::postings::{HashToIdx, MappedPostings, Postings};
+use crate::storage::{self, IndexFormat};
+use crate::Error;
+
+pub type DatasetID = usize;
+pub type SigCounter = counter::Counter<DatasetID>;
+
+/// Inverted index from hashes to the datasets containing them.
+///
+/// The JSON serialization is the same used by the sourmash greyhound index,
+/// so indices built by either can be loaded here.
+#[derive(Serialize, Deserialize)]
+pub struct RevIndex {
+    pub(crate) hash_to_idx: Postings,
+    pub(crate) sig_files: Vec<PathBuf>,
+    #[serde(skip)]
+    pub(crate) ref_sigs: Option<Vec<Signature>>,
+    /// Reference signatures loaded during gather, if a memory budget was set
+    #[serde(skip)]
+    pub(crate) cache: Option<SigCache>,
+    pub(crate) template: Sketch,
+    /// Name and md5 for each entry in `sig_files`.
+    /// Missing in indices built by older versions.
+    #[serde(default)]
+    pub(crate) datasets: Vec<DatasetInfo>,
+}
+
+#[derive(Getters, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct DatasetInfo {
+    #[getset(get = "pub")]
+    pub(crate) name: String,
+
+    #[getset(get = "pub")]
+    pub(crate) md5: String,
+}
+
+impl DatasetInfo {
+    /// The md5 is from the sketch as stored in the signature, before any
+    /// downsampling, so it matches the md5 in sourmash manifests and in
+    /// picklists built from them.
+    fn new(sig: &Signature, stored: &KmerMinHash) -> DatasetInfo {
+        DatasetInfo {
+            name: sig.name(),
+            md5: stored.md5sum(),
+        }
+    }
+}
+
+#[derive(CopyGetters, Getters, Serialize, Deserialize, Debug)]
+pub struct GatherResult {
+    #[serde(skip)]
+    #[getset(get_copy = "pub")]
+    dataset_id: DatasetID,
+
+    #[getset(get_copy = "pub")]
+    intersect_bp: usize,
+
+    #[getset(get_copy = "pub")]
+    f_orig_query: f64,
+
+    #[getset(get_copy = "pub")]
+    f_match: f64,
+
+    #[getset(get_copy = "pub")]
+    f_unique_to_query: f64,
+
+    #[getset(get_copy = "pub")]
+    f_unique_weighted: f64,
+
+    /// Rounded `abund_stats`, kept as integers for existing clients
+    #[getset(get_copy = "pub")]
+    average_abund: usize,
+
+    #[getset(get_copy = "pub")]
+    median_abund: usize,
+
+    #[getset(get_copy = "pub")]
+    std_abund: usize,
+
+    /// Query abundances for the hashes shared with this match. Only set for
+    /// queries with abundance.
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    abund_stats: AbundStats,
+
+    #[getset(get = "pub")]
+    filename: String,
+
+    #[getset(get = "pub")]
+    name: String,
+
+    #[getset(get = "pub")]
+    md5: String,
+
+    #[getset(get = "pub")]
+    match_: Signature,
+
+    #[getset(get_copy = "pub")]
+    f_match_orig: f64,
+
+    #[getset(get_copy = "pub")]
+    unique_intersect_bp: usize,
+
+    #[getset(get_copy = "pub")]
+    gather_result_rank: usize,
+
+    #[getset(get_copy = "pub")]
+    remaining_bp: usize,
+
+    /// Sum of query abundances for hashes assigned to this match and all
+    /// previous ones. Only set for queries with abundance.
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    sum_weighted_found: u64,
+
+    /// Sum of all query abundances. Only set for queries with abundance.
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    total_weighted_hashes: u64,
+
+    /// Mean query abundance over the hashes shared with this match, an
+    /// estimate of the match k-mer coverage in the query.
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    estimated_depth: f64,
+
+    /// ANI estimated from the fraction of the query contained in the match.
+    /// For protein, dayhoff and hp indices this is an amino acid identity.
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    query_containment_ani: AniEstimate,
+
+    /// ANI estimated from the fraction of the match contained in the query,
+    /// an amino acid identity for protein, dayhoff and hp indices
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    match_containment_ani: AniEstimate,
+}
+
+impl GatherResult {
+    pub fn average_containment_ani(&self) -> f64 {
+        (self.query_containment_ani.ani() + self.match_containment_ani.ani()) / 2.
+    }
+
+    pub fn max_containment_ani(&self) -> f64 {
+        self.query_containment_ani
+            .ani()
+            .max(self.match_containment_ani.ani())
+    }
+}
+
+/// A dataset sharing hashes with a search query.
+#[derive(CopyGetters, Getters, Serialize, Deserialize, Debug)]
+pub struct SearchResult {
+    #[serde(skip)]
+    #[getset(get_copy = "pub")]
+    dataset_id: DatasetID,
+
+    #[getset(get = "pub")]
+    filename: String,
+
+    /// Empty for indices built without dataset metadata
+    #[getset(get = "pub")]
+    name: String,
+
+    /// Empty for indices built without dataset metadata
+    #[getset(get = "pub")]
+    md5: String,
+
+    #[getset(get_copy = "pub")]
+    intersect_hashes: usize,
+
+    /// Fraction of the query contained in the dataset
+    #[getset(get_copy = "pub")]
+    containment: f64,
+
+    /// Score used for sorting results: the containment, or the Jaccard
+    /// similarity for similarity searches
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    similarity: f64,
+
+    /// Amino acid identity for protein, dayhoff and hp indices
+    #[getset(get_copy = "pub")]
+    query_containment_ani: AniEstimate,
+}
+
+impl RevIndex {
+    /// Build a new index from a list of signature paths.
+    ///
+    /// If `queries` is provided only hashes present in the queries are kept,
+    /// and datasets sharing fewer than `threshold` hashes with every query
+    /// are skipped.
+    pub fn new(
+        search_sigs: &[PathBuf],
+        template: &Sketch,
+        threshold: usize,
+        queries: Option<&[KmerMinHash]>,
+        keep_sigs: bool,
+    ) -> Result<RevIndex, Error> {
+        let query_hashes = queries.map(Self::query_hashes);
+
+        let processed_sigs = AtomicUsize::new(0);
+        let (mut hash_to_idx, mut datasets) = search_sigs
+            .par_iter()
+            .enumerate()
+            .map(|(dataset_id, filename)| -> Result<_, Error> {
+                let i = processed_sigs.fetch_add(1, Ordering::SeqCst);
+                if i % 1000 == 0 {
+                    info!("Processed {} reference sigs", i);
+                }
+
+                let search_sig = load_reference(filename)?;
+
+                let (info, hashes) = Self::map_hashes(
+                    dataset_id,
+                    filename,
+                    &search_sig,
+                    template,
+                    threshold,
+                    queries,
+                    query_hashes.as_ref(),
+                );
+                Ok((dataset_id, info, hashes))
+            })
+            .try_fold(
+                || (HashToIdx::default(), vec![]),
+                |(postings, mut datasets), loaded| -> Result<_, Error> {
+                    let (dataset_id, info, hashes) = loaded?;
+                    datasets.push((dataset_id, info));
+                    Ok(match hashes {
+                        Some(hashes) => (Self::merge_postings(postings, hashes), datasets),
+                        None => (postings, datasets),
+                    })
+                },
+            )
+            .try_reduce(
+                || (HashToIdx::default(), vec![]),
+                |(a, mut datasets_a), (b, datasets_b)| {
+                    datasets_a.extend(datasets_b);
+                    Ok((Self::merge_postings(a, b), datasets_a))
+                },
+            )?;
+
+        hash_to_idx
+            .values_mut()
+            .for_each(|datasets| datasets.sort_unstable());
+        datasets.sort_unstable_by_key(|(dataset_id, _)| *dataset_id);
+
+        let ref_sigs = if keep_sigs {
+            let ref_sigs = search_sigs
+                .par_iter()
+                .map(|ref_path| {
+                    // Keep the signature with the sketch used for the index,
+                    // since it is the only one `load_match` will look at
+                    let sigs = load_reference(ref_path)?;
+                    Ok(match select_sketch(&sigs, template) {
+                        Ok(Some((sig, _, _))) => sig.clone(),
+                        _ => Signature::default(),
+                    })
+                })
+                .collect::<Result<_, Error>>()?;
+            Some(ref_sigs)
+        } else {
+            None
+        };
+
+        Ok(RevIndex {
+            hash_to_idx: Postings::Memory(hash_to_idx),
+            sig_files: search_sigs.into(),
+            ref_sigs,
+            cache: None,
+            template: template.clone(),
+            datasets: datasets.into_iter().map(|(_, info)| info).collect(),
+        })
+    }
+
+    /// Load an index, autodetecting between the binary and JSON formats.
+    ///
+    /// If `queries` is provided only hashes present in the queries are kept.
+    pub fn load<P: AsRef<Path>>(
+        index_path: P,
+        queries: Option<&[KmerMinHash]>,
+    ) -> Result<RevIndex, Error> {
+        let query_hashes = queries.map(Self::query_hashes);
+
+        match IndexFormat::detect(&index_path)? {
+            IndexFormat::Binary => {
+                let (header, hash_to_idx) =
+                    storage::load_binary(&index_path, query_hashes.as_ref())?;
+                Ok(RevIndex {
+                    hash_to_idx: Postings::Memory(hash_to_idx),
+                    sig_files: header.sig_files.into_iter().map(PathBuf::from).collect(),
+                    ref_sigs: None,
+                    cache: None,
+                    template: header.template,
+                    datasets: header.datasets,
+                })
+            }
+            IndexFormat::Json => {
+                let (rdr, _) = niffler::from_path(&index_path)?;
+                let mut revindex: RevIndex = serde_json::from_reader(rdr)?;
+                if let Postings::Memory(map) = &mut revindex.hash_to_idx {
+                    if let Some(hashes) = query_hashes {
+                        map.retain(|hash, _| hashes.contains(hash));
+                    }
+                    // Indices built by sourmash don't keep postings sorted,
+                    // but the binary format and `append` rely on it
+                    map.values_mut().for_each(|datasets| {
+                        datasets.sort_unstable();
+                        datasets.dedup();
+                    });
+                }
+                Ok(revindex)
+            }
+        }
+    }
+
+    /// Open a binary index without loading the postings into memory.
+    ///
+    /// Hashes are looked up directly from the memory-mapped file, so startup
+    /// doesn't depend on the index size and the page cache is shared between
+    /// processes serving the same index.
+    pub fn load_mmap<P: AsRef<Path>>(index_path: P) -> Result<RevIndex, Error> {
+        if IndexFormat::detect(&index_path)? != IndexFormat::Binary {
+            return Err(Error::InvalidIndex(
+                "only binary indices can be memory-mapped".into(),
+            ));
+        }
+
+        let (header, postings) = MappedPostings::open(&index_path)?;
+        Ok(RevIndex {
+            hash_to_idx: Postings::Mapped(postings),
+            sig_files: header.sig_files.into_iter().map(PathBuf::from).collect(),
+            ref_sigs: None,
+            cache: None,
+            template: header.template,
+            datasets: header.datasets,
+        })
+    }
+
+    /// Read only the template used to build an index.
+    ///
+    /// This is cheap for binary indices, but JSON indices still need to be
+    /// parsed completely (without keeping the postings in memory).
+    pub fn read_template<P: AsRef<Path>>(index_path: P) -> Result<Sketch, Error> {
+        #[derive(Deserialize)]
+        struct TemplateOnly {
+            template: Sketch,
+        }
+
+        match IndexFormat::detect(&index_path)? {
+            IndexFormat::Binary => {
+                let mut rdr = BufReader::new(File::open(index_path)?);
+                Ok(storage::read_header(&mut rdr)?.template)
+            }
+            IndexFormat::Json => {
+                let (rdr, _) = niffler::from_path(&index_path)?;
+                let index: TemplateOnly = serde_json::from_reader(rdr)?;
+                Ok(index.template)
+            }
+        }
+    }
+
+    /// Save the index. JSON indices are gzip-compressed.
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: IndexFormat) -> Result<(), Error> {
+        storage::write_atomic(path.as_ref(), |out| match format {
+            IndexFormat::Binary => storage::save_binary(self, out),
+            IndexFormat::Json => {
+                let wtr = niffler::get_writer(
+                    Box::new(out),
+                    niffler::compression::Format::Gzip,
+                    niffler::compression::Level::One,
+                )?;
+                serde_json::to_writer(wtr, &self)?;
+                Ok(())
+            }
+        })
+    }
+
+    fn query_hashes(queries: &[KmerMinHash]) -> HashSet<u64> {
+        queries
+            .iter()
+            .flat_map(|query| query.iter_mins().cloned())
+            .collect()
+    }
+
+    fn map_hashes(
+        dataset_id: DatasetID,
+        filename: &Path,
+        search_sig: &[Signature],
+        template: &Sketch,
+        threshold: usize,
+        queries: Option<&[KmerMinHash]>,
+        query_hashes: Option<&HashSet<u64>>,
+    ) -> (DatasetInfo, Option<HashToIdx>) {
+        let (sig, stored, search_mh) = match select_sketch(search_sig, template) {
+            Ok(Some(selected)) => selected,
+            Ok(None) => return (DatasetInfo::default(), None),
+            Err(e) => {
+                warn!("Skipping {:?}: {}", filename, e);
+                return (DatasetInfo::default(), None);
+            }
+        };
+        let info = DatasetInfo::new(sig, stored);
+
+        let matched: Vec<u64> = if let Some(hashes) = query_hashes {
+            search_mh
+                .iter_mins()
+                .filter(|hash| hashes.contains(hash))
+                .cloned()
+                .collect()
+        } else {
+            search_mh.iter_mins().cloned().collect()
+        };
+
+        if matched.is_empty() {
+            return (info, None);
+        }
+
+        if let Some(queries) = queries {
+            if threshold > 0 {
+                // mins are kept sorted in KmerMinHash, so `matched` is too
+                let passes = queries.iter().any(|query| {
+                    let shared = query
+                        .iter_mins()
+                        .filter(|hash| matched.binary_search(hash).is_ok())
+                        .count();
+                    shared >= threshold
+                });
+                if !passes {
+                    return (info, None);
+                }
+            }
+        }
+
+        let mut hash_to_idx = HashToIdx::default();
+        for hash in matched {
+            hash_to_idx.insert(hash, vec![dataset_id]);
+        }
+        (info, Some(hash_to_idx))
+    }
+
+    fn merge_postings(a: HashToIdx, b: HashToIdx) -> HashToIdx {
+        let (mut larger, smaller) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+        for (hash, datasets) in smaller {
+            larger.entry(hash).or_default().extend(datasets);
+        }
+        larger
+    }
+
+    pub fn counter_for_query(&self, query: &KmerMinHash) -> SigCounter {
+        self.counter_for(query, None)
+    }
+
+    /// Build a counter for `query`. If `picked` (as returned by
+    /// `pick_datasets`) is set, only picked datasets are included.
+    pub fn counter_for(&self, query: &KmerMinHash, picked: Option<&[bool]>) -> SigCounter {
+        let datasets = query
+            .iter_mins()
+            .filter_map(|hash| self.hash_to_idx.get(*hash))
+            .flat_map(|datasets| datasets.into_owned());
+        match picked {
+            Some(picked) => datasets.filter(|dataset_id| picked[*dataset_id]).collect(),
+            None => datasets.collect(),
+        }
+    }
+
+    /// Hashes in `query` present in any of `datasets`, sorted.
+    pub fn shared_hashes(&self, query: &KmerMinHash, datasets: &HashSet<DatasetID>) -> Vec<u64> {
+        query
+            .iter_mins()
+            .filter(|hash| {
+                self.hash_to_idx
+                    .get(**hash)
+                    .map_or(false, |ids| ids.iter().any(|id| datasets.contains(id)))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Find which datasets pass all `picklists`, indexed by dataset ID.
+    ///
+    /// Dataset names and md5s are loaded first if the index doesn't have them.
+    pub fn pick_datasets(&mut self, picklists: &[Picklist]) -> Result<Vec<bool>, Error> {
+        self.load_dataset_info()?;
+        Ok(self
+            .datasets
+            .iter()
+            .map(|info| picklists.iter().all(|picklist| picklist.matches(info)))
+            .collect())
+    }
+
+    pub fn template(&self) -> Sketch {
+        self.template.clone()
+    }
+
+    /// Number of datasets with a sketch compatible with the index template.
+    /// Only known for indices with dataset metadata.
+    pub fn n_compatible(&self) -> usize {
+        self.datasets
+            .iter()
+            .filter(|info| !info.md5.is_empty())
+            .count()
+    }
+
+    /// Find datasets sharing at least a fraction `threshold` of the query,
+    /// scored by the containment of the query or, if `similarity` is set, by
+    /// Jaccard similarity. Results are sorted by decreasing score.
+    pub fn search(
+        &self,
+        counter: SigCounter,
+        similarity: bool,
+        threshold: f64,
+        query: &KmerMinHash,
+    ) -> Result<Vec<SearchResult>, Error> {
+        // Jaccard similarity is never larger than the containment of the
+        // query, so candidates are selected by containment and then rescored.
+        let min_shared = (threshold * query.size() as f64) as usize;
+        let mut matches = self.search_results(counter, min_shared.max(1), query);
+
+        if similarity {
+            for m in &mut matches {
+                let (_, match_mh) = self.load_match(m.dataset_id)?;
+                let union = query.size() + match_mh.size() - m.intersect_hashes;
+                m.similarity = m.intersect_hashes as f64 / union as f64;
+            }
+            matches.retain(|m| m.similarity >= threshold);
+            matches.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        }
+        Ok(matches)
+    }
+
+    /// Find datasets sharing at least `threshold` hashes with `query`,
+    /// scored by the containment of the query.
+    pub fn search_results(
+        &self,
+        counter: SigCounter,
+        threshold: usize,
+        query: &KmerMinHash,
+    ) -> Vec<SearchResult> {
+        let ksize = identity_ksize(query);
+        counter
+            .most_common()
+            .into_iter()
+            .take_while(|(_, size)| *size >= threshold)
+            .map(|(dataset_id, size)| {
+                let info = self.datasets.get(dataset_id).cloned().unwrap_or_default();
+                let containment = size as f64 / query.size() as f64;
+                SearchResult {
+                    dataset_id,
+                    filename: self.sig_files[dataset_id].to_string_lossy().into(),
+                    name: info.name,
+                    md5: info.md5,
+                    intersect_hashes: size,
+                    containment,
+                    similarity: containment,
+                    query_containment_ani: AniEstimate::from_containment(size, query.size(), ksize),
+                }
+            })
+            .collect()
+    }
+
+    pub fn gather(
+        &self,
+        mut counter: SigCounter,
+        threshold: usize,
+        query: &KmerMinHash,
+    ) -> Result<Vec<GatherResult>, Error> {
+        let query_size = query.size() as f64;
+        let mut remaining: HashSet<u64> = query.iter_mins().cloned().collect();
+
+        // Abundance-weighted results are only reported for queries with abundance
+        let abunds: Option<HashMap<u64, u64>> = if query.track_abundance() {
+            Some(query.to_vec_abunds().into_iter().collect())
+        } else {
+            None
+        };
+        let ksize = identity_ksize(query);
+        let total_weighted_hashes: u64 = abunds.as_ref().map_or(0, |a| a.values().sum());
+        let mut sum_weighted_found = 0;
+
+        let mut match_size = usize::max_value();
+        let mut matches = vec![];
+
+        while match_size > threshold && !counter.is_empty() {
+            let (dataset_id, size) = counter.most_common()[0];
+            match_size = if size >= threshold { size } else { break };
+
+            let (match_sig, match_mh) = self.load_match(dataset_id)?;
+            let scaled = match_mh.scaled() as usize;
+
+            let (intersect_orig, _) = match_mh.intersection_size(query)?;
+            let unique: Vec<u64> = match_mh
+                .iter_mins()
+                .filter(|hash| remaining.contains(hash))
+                .cloned()
+                .collect();
+
+            let f_orig_query = intersect_orig as f64 / query_size;
+            let f_match = intersect_orig as f64 / match_mh.size() as f64;
+            let f_unique_to_query = unique.len() as f64 / query_size;
+
+            let mut f_unique_weighted = f_unique_to_query;
+            let mut abund_stats = AbundStats::default();
+            if let Some(abunds) = &abunds {
+                let weighted_found: u64 = unique.iter().map(|hash| abunds[hash]).sum();
+                sum_weighted_found += weighted_found;
+                f_unique_weighted = weighted_found as f64 / total_weighted_hashes as f64;
+
+                let mut intersect_abunds: Vec<u64> = match_mh
+                    .iter_mins()
+                    .filter_map(|hash| abunds.get(hash).cloned())
+                    .collect();
+                abund_stats = AbundStats::new(&mut intersect_abunds);
+            }
+
+            // Prepare counter for finding the next match by decrementing
+            // all hashes found in the current match in other datasets
+            for hash in &unique {
+                remaining.remove(hash);
+                if let Some(datasets) = self.hash_to_idx.get(*hash) {
+                    for dataset in datasets.iter() {
+                        counter.entry(*dataset).and_modify(|e| {
+                            if *e > 0 {
+                                *e -= 1
+                            }
+                        });
+                    }
+                }
+            }
+            counter.remove(&dataset_id);
+
+            matches.push(GatherResult {
+                dataset_id,
+                intersect_bp: intersect_orig as usize * scaled,
+                f_orig_query,
+                f_match,
+                f_unique_to_query,
+                f_unique_weighted,
+                average_abund: abund_stats.mean.round() as usize,
+                median_abund: abund_stats.median.round() as usize,
+                std_abund: abund_stats.std.round() as usize,
+                abund_stats,
+                filename: self.sig_files[dataset_id].to_string_lossy().into(),
+                name: match_sig.name(),
+                md5: self.dataset_md5(dataset_id, &match_sig)?,
+                f_match_orig: f_match,
+                unique_intersect_bp: unique.len() * scaled,
+                gather_result_rank: matches.len(),
+                remaining_bp: remaining.len() * scaled,
+                sum_weighted_found,
+                total_weighted_hashes,
+                estimated_depth: abund_stats.mean,
+                query_containment_ani: AniEstimate::from_containment(
+                    intersect_orig as usize,
+                    query.size(),
+                    ksize,
+                ),
+                match_containment_ani: AniEstimate::from_containment(
+                    intersect_orig as usize,
+                    match_mh.size(),
+                    ksize,
+                ),
+                match_: match_sig,
+            });
+        }
+
+        Ok(matches)
+    }
+
+    /// Keep reference signatures loaded during gather in memory, using up to
+    /// `max_bytes` (estimated). The least recently used are dropped first.
+    ///
+    /// This replaces signatures kept by `new` with `keep_sigs`.
+    pub fn set_cache(&mut self, max_bytes: usize) {
+        self.ref_sigs = None;
+        self.cache = Some(SigCache::new(max_bytes));
+    }
+
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(SigCache::stats)
+    }
+
+    /// Load the signature for a dataset, and the sketch compatible with the
+    /// index template.
+    fn load_match(&self, dataset_id: DatasetID) -> Result<(Signature, KmerMinHash), Error> {
+        if let Some(cached) = self.cache.as_ref().and_then(|c| c.get(dataset_id)) {
+            return Ok((cached.0.clone(), cached.1.clone()));
+        }
+
+        let sigs = if let Some(ref_sigs) = &self.ref_sigs {
+            vec![ref_sigs[dataset_id].clone()]
+        } else {
+            load_signatures(&self.sig_files[dataset_id])?
+        };
+
+        match select_minhash(&sigs, &self.template)? {
+            Some((sig, mh)) => {
+                let (sig, mh) = (sig.clone(), mh.into_owned());
+                if let Some(cache) = &self.cache {
+                    cache.insert(dataset_id, sig.clone(), mh.clone());
+                }
+                Ok((sig, mh))
+            }
+            None => Err(Error::NoCompatibleSketch(
+                self.sig_files[dataset_id].to_string_lossy().into(),
+            )),
+        }
+    }
+
+    /// md5 of the sketch selected for a dataset, as in `DatasetInfo`.
+    fn dataset_md5(&self, dataset_id: DatasetID, sig: &Signature) -> Result<String, Error> {
+        if let Some(info) = self.datasets.get(dataset_id) {
+            return Ok(info.md5.clone());
+        }
+        match select_sketch(std::slice::from_ref(sig), &self.template)? {
+            Some((_, stored, _)) => Ok(stored.md5sum()),
+            None => Err(Error::NoCompatibleSketch(
+                self.sig_files[dataset_id].to_string_lossy().into(),
+            )),
+        }
+    }
+
+    /// Check that `template` has the same ksize, scaled and molecule type
+    /// as the one used to build this index.
+    pub fn check_template(&self, template: &Sketch) -> Result<(), Error> {
+        if let (Sketch::MinHash(ours), Sketch::MinHash(theirs)) = (&self.template, template) {
+            if ours.ksize() == theirs.ksize()
+                && ours.max_hash() == theirs.max_hash()
+                && ours.hash_function() == theirs.hash_function()
+            {
+                return Ok(());
+            }
+        }
+
+        Err(Error::IncompatibleTemplate(format!(
+            "index uses {}, not {}",
+            describe_template(&self.template),
+            describe_template(template)
+        )))
+    }
+
+    /// Fill in name and md5 for datasets in indices built without them.
+    pub fn load_dataset_info(&mut self) -> Result<(), Error> {
+        if self.datasets.len() == self.sig_files.len() {
+            return Ok(());
+        }
+
+        info!("Index has no dataset metadata, loading reference signatures");
+        let template = &self.template;
+        self.datasets = self
+            .sig_files
+            .par_iter()
+            .map(|path| {
+                let sigs = load_signatures(path)?;
+                Ok(match select_sketch(&sigs, template)? {
+                    Some((sig, stored, _)) => DatasetInfo::new(sig, stored),
+                    None => DatasetInfo::default(),
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(())
+    }
+
+    /// Add new signatures to the index, skipping signatures with the same
+    /// md5 as a dataset already in the index.
+    ///
+    /// Returns the number of datasets added.
+    pub fn append(&mut self, new_sigs: &[PathBuf]) -> Result<usize, Error> {
+        if let Postings::Mapped(_) = self.hash_to_idx {
+            return Err(Error::ReadOnlyIndex);
+        }
+        self.load_dataset_info()?;
+
+        let template = &self.template;
+        let loaded = new_sigs
+            .par_iter()
+            .map(|path| {
+                let sigs = load_signatures(path)?;
+                Ok(select_sketch(&sigs, template)?.map(|(sig, stored, mh)| {
+                    (DatasetInfo::new(sig, stored), mh.mins(), sig.clone())
+                }))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut seen: HashSet<String> = self.datasets.iter().map(|d| d.md5.clone()).collect();
+        let map = match &mut self.hash_to_idx {
+            Postings::Memory(map) => map,
+            Postings::Mapped(_) => unreachable!(),
+        };
+
+        let mut added = 0;
+        for (path, entry) in new_sigs.iter().zip(loaded) {
+            let (info, hashes, sig) = match entry {
+                Some(entry) => entry,
+                None => {
+                    warn!("No compatible sketch in {:?}, skipping", path);
+                    continue;
+                }
+            };
+
+            if !seen.insert(info.md5.clone()) {
+                info!(
+                    "{:?} ({}) is already in the index, skipping",
+                    path, info.md5
+                );
+                continue;
+            }
+
+            // New ids are always larger, so postings stay sorted
+            let dataset_id = self.sig_files.len();
+            for hash in hashes {
+                map.entry(hash).or_default().push(dataset_id);
+            }
+            self.sig_files.push(path.clone());
+            self.datasets.push(info);
+            if let Some(ref_sigs) = &mut self.ref_sigs {
+                ref_sigs.push(sig);
+            }
+            added += 1;
+        }
+
+        Ok(added)
+    }
+
+    /// Add all datasets from `other` to this index. Datasets from `other`
+    /// are renumbered to come after the ones already in this index.
+    pub fn merge(&mut self, other: RevIndex) -> Result<(), Error> {
+        self.check_template(&other.template)?;
+
+        let map = match &mut self.hash_to_idx {
+            Postings::Memory(map) => map,
+            Postings::Mapped(_) => return Err(Error::ReadOnlyIndex),
+        };
+
+        // All ids from `other` are larger, so postings stay sorted
+        let offset = self.sig_files.len();
+        let mut add_posting = |hash: u64, datasets: &[DatasetID]| {
+            map.entry(hash)
+                .or_default()
+                .extend(datasets.iter().map(|dataset_id| dataset_id + offset));
+        };
+        match &other.hash_to_idx {
+            Postings::Memory(other_map) => {
+                for (hash, datasets) in other_map {
+                    add_posting(*hash, datasets);
+                }
+            }
+            Postings::Mapped(mapped) => {
+                for posting in mapped.iter() {
+                    let (hash, datasets) = posting?;
+                    add_posting(hash, &datasets);
+                }
+            }
+        }
+
+        if self.datasets.len() == self.sig_files.len()
+            && other.datasets.len() == other.sig_files.len()
+        {
+            self.datasets.extend(other.datasets);
+        } else if !self.datasets.is_empty() || !other.datasets.is_empty() {
+            warn!("Not all merged indices have dataset metadata, discarding it");
+            self.datasets.clear();
+        }
+
+        self.ref_sigs = match (self.ref_sigs.take(), other.ref_sigs) {
+            (Some(mut ours), Some(theirs)) => {
+                ours.extend(theirs);
+                Some(ours)
+            }
+            _ => None,
+        };
+
+        self.sig_files.extend(other.sig_files);
+
+        Ok(())
+    }
+
+    /// Find datasets matching `key` by signature path, name or md5.
+    ///
+    /// Names and md5s are only available after `load_dataset_info`.
+    pub fn find_datasets(&self, key: &str) -> Vec<DatasetID> {
+        let empty = DatasetInfo::default();
+        self.sig_files
+            .iter()
+            .enumerate()
+            .filter(|(dataset_id, path)| {
+                let info = self.datasets.get(*dataset_id).unwrap_or(&empty);
+                path.to_string_lossy() == key || info.name == key || info.md5 == key
+            })
+            .map(|(dataset_id, _)| dataset_id)
+            .collect()
+    }
+
+    /// Remove datasets from the index. Remaining datasets are renumbered.
+    ///
+    /// Returns the number of hashes that are not present in any dataset
+    /// anymore (and so were removed too).
+    pub fn remove(&mut self, to_remove: &HashSet<DatasetID>) -> Result<usize, Error> {
+        let map = match &mut self.hash_to_idx {
+            Postings::Memory(map) => map,
+            Postings::Mapped(_) => return Err(Error::ReadOnlyIndex),
+        };
+
+        let mut next_id = 0;
+        let new_ids: Vec<Option<DatasetID>> = (0..self.sig_files.len())
+            .map(|dataset_id| {
+                if to_remove.contains(&dataset_id) {
+                    None
+                } else {
+                    next_id += 1;
+                    Some(next_id - 1)
+                }
+            })
+            .collect();
+
+        let n_hashes = map.len();
+        // Renumbering keeps the relative order, so postings stay sorted
+        map.retain(|_, datasets| {
+            *datasets = datasets
+                .iter()
+                .filter_map(|dataset_id| new_ids[*dataset_id])
+                .collect();
+            !datasets.is_empty()
+        });
+        let orphaned = n_hashes - map.len();
+
+        if self.datasets.len() == self.sig_files.len() {
+            retain_by_id(&mut self.datasets, &new_ids);
+        }
+        if let Some(ref_sigs) = &mut self.ref_sigs {
+            retain_by_id(ref_sigs, &new_ids);
+        }
+        // Cached signatures use the old IDs
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+        retain_by_id(&mut self.sig_files, &new_ids);
+
+        Ok(orphaned)
+    }
+}
+
+/// Summary of query abundances for the hashes shared with a match.
+#[derive(CopyGetters, Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct AbundStats {
+    #[getset(get_copy = "pub")]
+    mean: f64,
+
+    #[getset(get_copy = "pub")]
+    median: f64,
+
+    #[getset(get_copy = "pub")]
+    std: f64,
+}
+
+impl AbundStats {
+    fn new(abunds: &mut [u64]) -> AbundStats {
+        if abunds.is_empty() {
+            return AbundStats::default();
+        }
+
+        abunds.sort_unstable();
+        let n = abunds.len();
+        let median = if n % 2 == 0 {
+            (abunds[n / 2 - 1] + abunds[n / 2]) as f64 / 2.
+        } else {
+            abunds[n / 2] as f64
+        };
+
+        let mean = abunds.iter().sum::<u64>() as f64 / n as f64;
+        let variance = abunds
+            .iter()
+            .map(|a| (*a as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n as f64;
+
+        AbundStats {
+            mean,
+            median,
+            std: variance.sqrt(),
+        }
+    }
+}
+
+/// Find the first sketch compatible with `template` in a signature file.
+///
+/// Sketches with the same ksize and molecule type but a smaller scaled are
+/// downsampled to the template scaled. If the only sketches with the same
+/// ksize and molecule type have a larger scaled, they can't be compared with
+/// the template and `Error::CoarserSketch` is returned.
+pub fn select_minhash<'a>(
+    sigs: &'a [Signature],
+    template: &Sketch,
+) -> Result<Option<(&'a Signature, Cow<'a, KmerMinHash>)>, Error> {
+    Ok(select_sketch(sigs, template)?.map(|(sig, _, mh)| (sig, mh)))
+}
+
+/// Like `select_minhash`, also returning the sketch as stored in the
+/// signature, before downsampling.
+fn select_sketch<'a>(
+    sigs: &'a [Signature],
+    template: &Sketch,
+) -> Result<Option<(&'a Signature, &'a KmerMinHash, Cow<'a, KmerMinHash>)>, Error> {
+    let template_mh = match template {
+        Sketch::MinHash(mh) => mh,
+        _ => return Ok(None),
+    };
+
+    // Avoid copying sketches when there is an exact match
+    for sig in sigs {
+        if let Some(Sketch::MinHash(mh)) = sig.select_sketch(template) {
+            return Ok(Some((sig, mh, Cow::Borrowed(mh))));
+        }
+    }
+
+    let mut coarser = None;
+    for sig in sigs {
+        for sketch in sig.sketches() {
+            if let Sketch::MinHash(mh) = sketch {
+                if mh.ksize() != template_mh.ksize()
+                    || mh.hash_function() != template_mh.hash_function()
+                    || mh.num() != 0
+                {
+                    continue;
+                }
+
+                if mh.max_hash() >= template_mh.max_hash() {
+                    let downsampled = mh.downsample_max_hash(template_mh.max_hash())?;
+                    return Ok(Some((sig, mh, Cow::Owned(downsampled))));
+                }
+                coarser = Some(mh.scaled());
+            }
+        }
+    }
+
+    match coarser {
+        Some(scaled) => Err(Error::CoarserSketch {
+            scaled,
+            template_scaled: template_mh.scaled(),
+        }),
+        None => Ok(None),
+    }
+}
+
+/// k-mer size for identity estimates. Protein, dayhoff and hp sketches store
+/// ksizes in nucleotides, but their k-mers are amino acids, so estimates for
+/// them are amino acid identities (AAI).
+fn identity_ksize(mh: &KmerMinHash) -> u32 {
+    match mh.hash_function() {
+        HashFunctions::murmur64_DNA => mh.ksize() as u32,
+        _ => mh.ksize() as u32 / 3,
+    }
+}
+
+/// Load a reference signature file, reporting which file failed.
+fn load_reference(path: &Path) -> Result<Vec<Signature>, Error> {
+    load_signatures(path).map_err(|e| Error::InvalidSignature {
+        path: path.to_string_lossy().into(),
+        source: Box::new(e),
+    })
+}
+
+fn retain_by_id<T>(items: &mut Vec<T>, new_ids: &[Option<DatasetID>]) {
+    let mut dataset_id = 0;
+    items.retain(|_| {
+        let keep = new_ids[dataset_id].is_some();
+        dataset_id += 1;
+        keep
+    });
+}
+
+/// Describe the ksize, scaled and molecule type of a template, for messages
+/// and for recording the parameters of a run.
+pub fn describe_template(template: &Sketch) -> String {
+    match template {
+        Sketch::MinHash(mh) => format!(
+            "k={} scaled={} moltype={}",
+            mh.ksize(),
+            mh.scaled(),
+            mh.hash_function()
+        ),
+        _ => "an unsupported sketch type".into(),
+    }
+}
@@ -0,0 +1,172 @@
+//! Summaries of what is inside an index: template, number of datasets and
+//! hashes, how widely hashes are shared, and an estimate of the memory used
+//! when it is loaded.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::mem::size_of;
+
+use serde::Serialize;
+use sourmash::sketch::Sketch;
+
+use crate::revindex::{DatasetID, RevIndex};
+use crate::Error;
+
+#[derive(Serialize, Debug)]
+pub struct IndexStats {
+    pub ksize: Option<usize>,
+    pub scaled: Option<u64>,
+    pub moltype: Option<String>,
+    pub n_datasets: usize,
+    pub n_hashes: usize,
+    pub n_postings: usize,
+    /// Number of hashes by how many datasets contain them, in power of 2 bins.
+    pub posting_histogram: Vec<HistogramBin>,
+    /// Hashes present in the largest number of datasets.
+    pub most_shared: Vec<SharedHash>,
+    /// Approximate memory used when the index is fully loaded.
+    pub estimated_memory_bytes: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HistogramBin {
+    pub min: usize,
+    pub max: usize,
+    pub count: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SharedHash {
+    pub hash: u64,
+    pub n_datasets: usize,
+}
+
+impl RevIndex {
+    /// Summarize the index contents, reporting the `top` most shared hashes.
+    pub fn stats(&self, top: usize) -> Result<IndexStats, Error> {
+        let mut n_postings = 0;
+        let mut histogram: Vec<usize> = vec![];
+        let mut most_shared = BinaryHeap::with_capacity(top + 1);
+
+        self.hash_to_idx.try_for_each(|hash, datasets| {
+            n_postings += datasets.len();
+
+            // bin 0 is for 1 dataset, bin 1 for 2, bin 2 for 3-4, bin 3 for 5-8...
+            let bin = datasets.len().next_power_of_two().trailing_zeros() as usize;
+            if histogram.len() <= bin {
+                histogram.resize(bin + 1, 0);
+            }
+            histogram[bin] += 1;
+
+            if top > 0 {
+                most_shared.push(Reverse((datasets.len(), hash)));
+                if most_shared.len() > top {
+                    most_shared.pop();
+                }
+            }
+        })?;
+
+        let posting_histogram = histogram
+            .into_iter()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .map(|(bin, count)| {
+                let max = 1 << bin;
+                let min = if bin == 0 { 1 } else { (max >> 1) + 1 };
+                HistogramBin { min, max, count }
+            })
+            .collect();
+
+        let most_shared = most_shared
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((n_datasets, hash))| SharedHash { hash, n_datasets })
+            .collect();
+
+        let (ksize, scaled, moltype) = match &self.template {
+            Sketch::MinHash(mh) => (
+                Some(mh.ksize()),
+                Some(mh.scaled()),
+                Some(mh.hash_function().to_string()),
+            ),
+            _ => (None, None, None),
+        };
+
+        Ok(IndexStats {
+            ksize,
+            scaled,
+            moltype,
+            n_datasets: self.sig_files.len(),
+            n_hashes: self.hash_to_idx.len(),
+            n_postings,
+            posting_histogram,
+            most_shared,
+            estimated_memory_bytes: self.estimated_memory(n_postings),
+        })
+    }
+
+    fn estimated_memory(&self, n_postings: usize) -> usize {
+        // hashbrown keeps at most 7/8 of the buckets full, with one control
+        // byte per bucket
+        let entry = size_of::<u64>() + size_of::<Vec<DatasetID>>() + 1;
+        let map = self.hash_to_idx.len() * entry * 8 / 7;
+        let postings = n_postings * size_of::<DatasetID>();
+
+        let sig_files: usize = self
+            .sig_files
+            .iter()
+            .map(|p| p.as_os_str().len() + size_of::<std::path::PathBuf>())
+            .sum();
+        let datasets: usize = self
+            .datasets
+            .iter()
+            .map(|d| d.name.len() + d.md5.len() + 2 * size_of::<String>())
+            .sum();
+
+        map + postings + sig_files + datasets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sourmash::sketch::minhash::KmerMinHash;
+
+    use super::*;
+    use crate::postings::{HashToIdx, Postings};
+
+    #[test]
+    fn histogram_and_most_shared() {
+        // Hash `n` is in the first `n` datasets
+        let mut hash_to_idx = HashToIdx::default();
+        for n in 1..=5 {
+            hash_to_idx.insert(n as u64, (0..n).collect());
+        }
+        let revindex = RevIndex {
+            hash_to_idx: Postings::Memory(hash_to_idx),
+            sig_files: (0..5).map(|i| format!("{}.sig", i).into()).collect(),
+            ref_sigs: None,
+            cache: None,
+            template: Sketch::MinHash(KmerMinHash::builder().num(0u32).ksize(31).build()),
+            datasets: vec![],
+        };
+
+        let stats = revindex.stats(2).unwrap();
+        assert_eq!(stats.n_datasets, 5);
+        assert_eq!(stats.n_hashes, 5);
+        assert_eq!(stats.n_postings, 1 + 2 + 3 + 4 + 5);
+
+        let bins: Vec<_> = stats
+            .posting_histogram
+            .iter()
+            .map(|bin| (bin.min, bin.max, bin.count))
+            .collect();
+        assert_eq!(bins, vec![(1, 1, 1), (2, 2, 1), (3, 4, 2), (5, 8, 1)]);
+
+        let most_shared: Vec<_> = stats
+            .most_shared
+            .iter()
+            .map(|shared| (shared.hash, shared.n_datasets))
+            .collect();
+        assert_eq!(most_shared, vec![(5, 5), (4, 4)]);
+    }
+}
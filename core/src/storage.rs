@@ -0,0 +1,546 @@
+//! Binary on-disk format for `RevIndex`.
+//!
+//! Layout (all fixed-width integers are little-endian):
+//!
+//! ```text
+//! magic           8 bytes, "GRHNDIDX"
+//! version         u32
+//! template        varint length + JSON-encoded Sketch
+//! sig_files       varint count, then for each dataset: path, name and md5
+//!                 (each as varint length + UTF-8). Version 1 only has paths.
+//! n_hashes        varint
+//! postings        blocks of up to BLOCK_SIZE hashes, sorted by hash.
+//!                 Each entry is: hash delta (varint, restarting from 0 at
+//!                 every block), number of datasets (varint), dataset ids
+//!                 delta-encoded (varint).
+//! block index     u64 count, then (first hash: u64, offset: u64) per block
+//! footer          u64 offset of the block index
+//! ```
+//!
+//! The block index is not needed for sequential loading, but allows looking
+//! up a hash without decoding the whole postings section (see
+//! `MappedPostings`).
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use sourmash::sketch::Sketch;
+
+use crate::postings::HashToIdx;
+use crate::revindex::{DatasetID, DatasetInfo, RevIndex};
+use crate::Error;
+
+pub(crate) const MAGIC: &[u8; 8] = b"GRHNDIDX";
+pub(crate) const VERSION: u32 = 2;
+pub(crate) const BLOCK_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexFormat {
+    /// Gzipped (or plain) JSON, compatible with the sourmash greyhound index.
+    Json,
+    /// Versioned binary format with delta-encoded postings.
+    Binary,
+}
+
+impl IndexFormat {
+    pub const VARIANTS: &'static [&'static str] = &["binary", "json"];
+
+    /// Detect the format of an index file by checking for the binary magic.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<IndexFormat, Error> {
+        let mut magic = [0u8; 8];
+        let mut file = File::open(path)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) if &magic == MAGIC => Ok(IndexFormat::Binary),
+            Ok(()) => Ok(IndexFormat::Json),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(IndexFormat::Json),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl FromStr for IndexFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(IndexFormat::Json),
+            "binary" => Ok(IndexFormat::Binary),
+            _ => Err(format!("unknown index format: {}", s)),
+        }
+    }
+}
+
+pub(crate) fn write_varint<W: Write>(wtr: &mut W, mut value: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut i = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[i] = byte;
+            i += 1;
+            break;
+        }
+        buf[i] = byte | 0x80;
+        i += 1;
+    }
+    wtr.write_all(&buf[..i])
+}
+
+pub(crate) fn read_varint<R: Read>(rdr: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    let mut byte = [0u8; 1];
+    loop {
+        rdr.read_exact(&mut byte)?;
+        if shift >= 64 {
+            return Err(invalid_data("varint is too long"));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn read_u32<R: Read>(rdr: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    rdr.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_bytes<R: Read>(rdr: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_varint(rdr)?;
+    // Read up to `len` instead of allocating it upfront, so a corrupted
+    // length fails with an error
+    let mut buf = vec![];
+    rdr.by_ref().take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
+
+fn read_string<R: Read>(rdr: &mut R) -> Result<String, Error> {
+    String::from_utf8(read_bytes(rdr)?).map_err(|e| Error::InvalidIndex(format!("{}", e)))
+}
+
+fn write_bytes<W: Write>(wtr: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_varint(wtr, bytes.len() as u64)?;
+    wtr.write_all(bytes)
+}
+
+/// Keeps track of how many bytes were written, to record block offsets.
+struct CountingWriter<W> {
+    inner: W,
+    offset: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn save_binary<W: Write>(revindex: &RevIndex, out: W) -> Result<(), Error> {
+    let mut wtr = CountingWriter {
+        inner: out,
+        offset: 0,
+    };
+
+    wtr.write_all(MAGIC)?;
+    wtr.write_all(&VERSION.to_le_bytes())?;
+    write_bytes(&mut wtr, &serde_json::to_vec(&revindex.template)?)?;
+
+    write_varint(&mut wtr, revindex.sig_files.len() as u64)?;
+    let empty = DatasetInfo::default();
+    for (i, path) in revindex.sig_files.iter().enumerate() {
+        let info = revindex.datasets.get(i).unwrap_or(&empty);
+        write_bytes(&mut wtr, path.to_string_lossy().as_bytes())?;
+        write_bytes(&mut wtr, info.name.as_bytes())?;
+        write_bytes(&mut wtr, info.md5.as_bytes())?;
+    }
+
+    let postings = revindex.hash_to_idx.sorted()?;
+    write_varint(&mut wtr, postings.len() as u64)?;
+
+    let mut block_index = Vec::with_capacity(postings.len() / BLOCK_SIZE + 1);
+    for block in postings.chunks(BLOCK_SIZE) {
+        block_index.push((block[0].0, wtr.offset));
+
+        let mut previous_hash = 0;
+        for (hash, datasets) in block {
+            write_varint(&mut wtr, hash - previous_hash)?;
+            previous_hash = *hash;
+
+            write_varint(&mut wtr, datasets.len() as u64)?;
+            let mut previous_id = 0;
+            for dataset_id in datasets.iter() {
+                write_varint(&mut wtr, (dataset_id - previous_id) as u64)?;
+                previous_id = *dataset_id;
+            }
+        }
+    }
+
+    let index_offset = wtr.offset;
+    wtr.write_all(&(block_index.len() as u64).to_le_bytes())?;
+    for (first_hash, offset) in block_index {
+        wtr.write_all(&first_hash.to_le_bytes())?;
+        wtr.write_all(&offset.to_le_bytes())?;
+    }
+    wtr.write_all(&index_offset.to_le_bytes())?;
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Write `path` through a temporary file, renamed to `path` only after `f`
+/// succeeded and the data was synced to disk, so readers never see a
+/// partially written file.
+pub fn write_atomic<F, E>(path: &Path, f: F) -> Result<(), E>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), E>,
+    E: From<io::Error>,
+{
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    f(&mut out)?;
+    out.flush()?;
+    out.get_ref().sync_all()?;
+    drop(out);
+
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Everything in the binary format before the postings section.
+pub(crate) struct Header {
+    pub(crate) template: Sketch,
+    pub(crate) sig_files: Vec<String>,
+    pub(crate) datasets: Vec<DatasetInfo>,
+    pub(crate) n_hashes: u64,
+}
+
+pub(crate) fn read_header<R: Read>(rdr: &mut R) -> Result<Header, Error> {
+    let mut magic = [0u8; 8];
+    rdr.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::InvalidIndex("missing greyhound index header".into()));
+    }
+
+    let version = read_u32(rdr)?;
+    if version == 0 || version > VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let template = serde_json::from_slice(&read_bytes(rdr)?)?;
+
+    let n_sigs = read_varint(rdr)?;
+    let mut sig_files = vec![];
+    let mut datasets = vec![];
+    for _ in 0..n_sigs {
+        sig_files.push(read_string(rdr)?);
+        if version >= 2 {
+            datasets.push(DatasetInfo {
+                name: read_string(rdr)?,
+                md5: read_string(rdr)?,
+            });
+        }
+    }
+
+    let n_hashes = read_varint(rdr)?;
+
+    Ok(Header {
+        template,
+        sig_files,
+        datasets,
+        n_hashes,
+    })
+}
+
+/// Decode the postings for one hash, given the previous hash in the block.
+pub(crate) fn read_posting<R: Read>(
+    rdr: &mut R,
+    previous_hash: u64,
+) -> io::Result<(u64, Vec<DatasetID>)> {
+    let hash = read_hash(rdr, previous_hash)?;
+    let datasets = read_datasets(rdr)?;
+    Ok((hash, datasets))
+}
+
+/// Decode the hash of a posting, given the previous hash in the block.
+pub(crate) fn read_hash<R: Read>(rdr: &mut R, previous_hash: u64) -> io::Result<u64> {
+    previous_hash
+        .checked_add(read_varint(rdr)?)
+        .ok_or_else(|| invalid_data("hash delta overflows"))
+}
+
+/// Decode the delta-encoded dataset ids of a posting.
+pub(crate) fn read_datasets<R: Read>(rdr: &mut R) -> io::Result<Vec<DatasetID>> {
+    let n_datasets = read_varint(rdr)?;
+    // Don't trust the count for preallocating, in case the file is corrupted
+    let mut datasets = Vec::with_capacity(n_datasets.min(1024) as usize);
+    let mut previous_id: DatasetID = 0;
+    for _ in 0..n_datasets {
+        previous_id = previous_id
+            .checked_add(read_varint(rdr)? as DatasetID)
+            .ok_or_else(|| invalid_data("dataset id delta overflows"))?;
+        datasets.push(previous_id);
+    }
+    Ok(datasets)
+}
+
+/// Skip over the dataset ids of a posting.
+pub(crate) fn skip_datasets<R: Read>(rdr: &mut R) -> io::Result<()> {
+    let n_datasets = read_varint(rdr)?;
+    for _ in 0..n_datasets {
+        read_varint(rdr)?;
+    }
+    Ok(())
+}
+
+fn invalid_data(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+/// Load a binary index, keeping only hashes in `hashes` (if provided).
+pub(crate) fn load_binary<P: AsRef<Path>>(
+    path: P,
+    hashes: Option<&HashSet<u64>>,
+) -> Result<(Header, HashToIdx), Error> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    let header = read_header(&mut rdr)?;
+
+    let mut hash_to_idx = HashToIdx::default();
+    let mut previous_hash = 0;
+    for i in 0..header.n_hashes as usize {
+        if i % BLOCK_SIZE == 0 {
+            previous_hash = 0;
+        }
+        let (hash, datasets) = read_posting(&mut rdr, previous_hash)?;
+        previous_hash = hash;
+
+        if hashes.map_or(true, |h| h.contains(&hash)) {
+            hash_to_idx.insert(hash, datasets);
+        }
+    }
+
+    Ok((header, hash_to_idx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
+
+    use super::*;
+    use crate::postings::{MappedPostings, Postings};
+
+    fn template() -> Sketch {
+        Sketch::MinHash(
+            KmerMinHash::builder()
+                .num(0u32)
+                .ksize(31)
+                .max_hash(max_hash_for_scaled(1000))
+                .build(),
+        )
+    }
+
+    /// An index with postings spanning several blocks, including the
+    /// largest possible hash.
+    fn test_index() -> RevIndex {
+        let mut hash_to_idx = HashToIdx::default();
+        for i in 0..(2 * BLOCK_SIZE as u64 + 5) {
+            hash_to_idx.insert(i * 1_000_003, vec![(i % 3) as DatasetID, 3]);
+        }
+        hash_to_idx.insert(u64::max_value(), vec![0, 1, 2, 3]);
+
+        RevIndex {
+            hash_to_idx: Postings::Memory(hash_to_idx),
+            sig_files: (0..4).map(|i| format!("sigs/{}.sig", i).into()).collect(),
+            ref_sigs: None,
+            cache: None,
+            template: template(),
+            datasets: (0..4)
+                .map(|i| DatasetInfo {
+                    name: format!("dataset {}", i),
+                    md5: format!("{:032x}", i),
+                })
+                .collect(),
+        }
+    }
+
+    fn encode(value: u64) -> Vec<u8> {
+        let mut buf = vec![];
+        write_varint(&mut buf, value).unwrap();
+        buf
+    }
+
+    #[test]
+    fn binary_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        let revindex = test_index();
+        revindex.save(&path, IndexFormat::Binary).unwrap();
+
+        assert_eq!(IndexFormat::detect(&path).unwrap(), IndexFormat::Binary);
+
+        let (header, hash_to_idx) = load_binary(&path, None).unwrap();
+        let sig_files: Vec<PathBuf> = header.sig_files.iter().map(PathBuf::from).collect();
+        assert_eq!(sig_files, revindex.sig_files);
+        assert_eq!(header.datasets, revindex.datasets);
+        assert_eq!(header.n_hashes as usize, revindex.hash_to_idx.len());
+        assert_eq!(
+            serde_json::to_string(&header.template).unwrap(),
+            serde_json::to_string(&revindex.template).unwrap()
+        );
+        match &revindex.hash_to_idx {
+            Postings::Memory(expected) => assert_eq!(&hash_to_idx, expected),
+            Postings::Mapped(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn unsorted_json_to_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("index.json.gz");
+        let bin_path = dir.path().join("index.bin");
+
+        let mut revindex = test_index();
+        let mut unsorted = HashToIdx::default();
+        unsorted.insert(1, vec![3, 0, 2]);
+        unsorted.insert(2, vec![2, 1, 1]);
+        unsorted.insert(u64::max_value(), vec![3, 2, 1, 0]);
+        revindex.hash_to_idx = Postings::Memory(unsorted);
+        revindex.save(&json_path, IndexFormat::Json).unwrap();
+
+        let loaded = RevIndex::load(&json_path, None).unwrap();
+        loaded.save(&bin_path, IndexFormat::Binary).unwrap();
+
+        let (_, hash_to_idx) = load_binary(&bin_path, None).unwrap();
+        assert_eq!(hash_to_idx.len(), 3);
+        assert_eq!(hash_to_idx[&1], vec![0, 2, 3]);
+        assert_eq!(hash_to_idx[&2], vec![1, 2]);
+        assert_eq!(hash_to_idx[&u64::max_value()], vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn binary_load_selected_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        test_index().save(&path, IndexFormat::Binary).unwrap();
+
+        let wanted: HashSet<u64> = vec![0, 200 * 1_000_003, u64::max_value(), 42]
+            .into_iter()
+            .collect();
+        let (_, hash_to_idx) = load_binary(&path, Some(&wanted)).unwrap();
+        assert_eq!(hash_to_idx.len(), 3);
+        assert_eq!(hash_to_idx[&u64::max_value()], vec![0, 1, 2, 3]);
+        assert_eq!(hash_to_idx[&(200 * 1_000_003)], vec![2, 3]);
+    }
+
+    #[test]
+    fn mmap_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        test_index().save(&path, IndexFormat::Binary).unwrap();
+
+        let (_, postings) = MappedPostings::open(&path).unwrap();
+        assert_eq!(postings.get(0), Some(vec![0, 3]));
+        assert_eq!(postings.get(200 * 1_000_003), Some(vec![2, 3]));
+        assert_eq!(postings.get(u64::max_value()), Some(vec![0, 1, 2, 3]));
+        assert_eq!(postings.get(1), None);
+        assert_eq!(postings.iter().count(), 2 * BLOCK_SIZE + 6);
+    }
+
+    #[test]
+    fn mmap_rejects_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index.bin");
+        test_index().save(&path, IndexFormat::Binary).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        for len in &[data.len() - 1, data.len() - 20, data.len() / 2] {
+            let truncated = dir.path().join("truncated.bin");
+            std::fs::write(&truncated, &data[..*len]).unwrap();
+            match MappedPostings::open(&truncated) {
+                Err(Error::InvalidIndex(_)) => {}
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("truncated index was accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn read_v1_header() {
+        let mut buf = vec![];
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&1u32.to_le_bytes());
+        write_bytes(&mut buf, &serde_json::to_vec(&template()).unwrap()).unwrap();
+        write_varint(&mut buf, 2).unwrap();
+        write_bytes(&mut buf, b"a.sig").unwrap();
+        write_bytes(&mut buf, b"b.sig").unwrap();
+        write_varint(&mut buf, 0).unwrap();
+
+        let header = read_header(&mut &buf[..]).unwrap();
+        assert_eq!(header.sig_files, vec!["a.sig", "b.sig"]);
+        assert!(header.datasets.is_empty());
+        assert_eq!(header.n_hashes, 0);
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let mut buf = vec![];
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&(VERSION + 1).to_le_bytes());
+
+        match read_header(&mut &buf[..]) {
+            Err(Error::UnsupportedVersion(v)) => assert_eq!(v, VERSION + 1),
+            _ => panic!("expected an unsupported version error"),
+        }
+    }
+
+    #[test]
+    fn varint_edge_cases() {
+        assert_eq!(encode(0), vec![0]);
+        assert_eq!(encode(127), vec![0x7f]);
+        assert_eq!(encode(128), vec![0x80, 0x01]);
+        assert_eq!(encode(u64::max_value()).len(), 10);
+
+        for value in &[0, 1, 127, 128, 300, u64::max_value() - 1, u64::max_value()] {
+            let buf = encode(*value);
+            assert_eq!(read_varint(&mut &buf[..]).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn varint_truncated() {
+        let buf = encode(300);
+        let err = read_varint(&mut &buf[..1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let err = read_varint(&mut &[0u8; 0][..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn varint_too_long() {
+        let buf = [0xffu8; 11];
+        let err = read_varint(&mut &buf[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
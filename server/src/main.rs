@@ -1,10 +1,13 @@
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use sourmash::index::greyhound::{GatherResult, RevIndex};
+use greyhound_core::{
+    select_minhash, GatherResult, MultiIndex, RevIndex, SearchResult, TemplateEntry,
+};
 use sourmash::signature::{Signature, SigsTrait};
 use sourmash::sketch::minhash::{max_hash_for_scaled, KmerMinHash};
 use sourmash::sketch::Sketch;
@@ -14,7 +17,7 @@ use tide::{self, Body, Request};
 
 #[derive(StructOpt, Debug)]
 struct Cli {
-    /// Index to serve
+    /// Index (or container with several templates) to serve
     #[structopt(parse(from_os_str))]
     index_path: PathBuf,
 
@@ -22,6 +25,10 @@ struct Cli {
     #[structopt(long = "--from-file")]
     from_file: bool,
 
+    /// Memory-map a binary index instead of loading it into memory
+    #[structopt(long = "--mmap", conflicts_with = "from-file")]
+    mmap: bool,
+
     /// ksize
     #[structopt(short = "k", long = "ksize", default_value = "31")]
     ksize: u8,
@@ -33,7 +40,30 @@ struct Cli {
 
 #[derive(Clone)]
 struct RevIndexState {
-    revindex: Arc<RevIndex>,
+    indices: Arc<Vec<(TemplateEntry, RevIndex)>>,
+}
+
+/// Optional query parameters for picking the index template
+#[derive(Debug, Default, Deserialize)]
+struct TemplateQuery {
+    ksize: Option<u32>,
+    moltype: Option<String>,
+}
+
+impl TemplateQuery {
+    /// Check if `entry` has the requested parameters. As in the CLI, `ksize`
+    /// counts amino acids for protein, dayhoff and hp, while templates store
+    /// it in nucleotides.
+    fn matches(&self, entry: &TemplateEntry) -> bool {
+        let ksize = self.ksize.map(|ksize| {
+            if entry.moltype().eq_ignore_ascii_case("DNA") {
+                ksize
+            } else {
+                ksize * 3
+            }
+        });
+        entry.matches(ksize, self.moltype.as_deref(), None)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -41,24 +71,31 @@ enum Error {
     #[error("Signature is not compatible with index")]
     UnsupportedSignature,
 
-    #[error("Sketch is not compatible with index")]
-    UnsupportedSketch,
+    #[error("{0}")]
+    CoarserSketch(String),
+
+    #[error("No index with the requested template")]
+    UnknownTemplate,
 
     #[error("Couldn't load the index ({0})")]
     IndexLoading(String),
 
     #[error("Error during gather ({0})")]
     Gather(String),
+
+    #[error("Error during search ({0})")]
+    Search(String),
 }
 
 impl RevIndexState {
     fn load<P: AsRef<Path>>(
         path: P,
         from_file: bool,
+        mmap: bool,
         scaled: Option<usize>,
         ksize: Option<u8>,
     ) -> Result<Self, Error> {
-        let revindex = if from_file {
+        let indices = if from_file {
             let paths = BufReader::new(
                 File::open(path).map_err(|e| Error::IndexLoading(format!("{}", e)))?,
             );
@@ -78,52 +115,97 @@ impl RevIndexState {
                 .max_hash(max_hash)
                 .build();
 
-            RevIndex::new(&sigs, &Sketch::MinHash(template_mh), 0, None, true)
+            let revindex = RevIndex::new(&sigs, &Sketch::MinHash(template_mh), 0, None, true)
+                .map_err(|e| Error::IndexLoading(format!("{}", e)))?;
+            vec![Self::with_entry(revindex, "")?]
+        } else if MultiIndex::is_multi(&path) {
+            let multi =
+                MultiIndex::open(&path).map_err(|e| Error::IndexLoading(format!("{}", e)))?;
+            multi
+                .entries()
+                .iter()
+                .map(|entry| {
+                    let revindex = if mmap {
+                        multi.load_mmap(entry)
+                    } else {
+                        multi.load(entry, None)
+                    }
+                    .map_err(|e| Error::IndexLoading(format!("{}", e)))?;
+                    Ok((entry.clone(), revindex))
+                })
+                .collect::<Result<_, Error>>()?
         } else {
-            RevIndex::load(path, None).map_err(|e| Error::IndexLoading(format!("{}", e)))?
+            let revindex = if mmap {
+                RevIndex::load_mmap(&path)
+            } else {
+                RevIndex::load(&path, None)
+            }
+            .map_err(|e| Error::IndexLoading(format!("{}", e)))?;
+            vec![Self::with_entry(revindex, path.as_ref())?]
         };
 
         Ok(Self {
-            revindex: Arc::new(revindex),
+            indices: Arc::new(indices),
         })
     }
 
-    fn gather(&self, query: Signature) -> Result<Vec<GatherResult>, Error> {
-        if let Some(sketch) = query.select_sketch(&self.revindex.template()) {
-            if let Sketch::MinHash(mh) = sketch {
-                let counter = self.revindex.counter_for_query(&mh);
-                Ok(self
-                    .revindex
-                    .gather(counter, 0, mh)
-                    .map_err(|e| Error::Gather(format!("{}", e)))?)
-            } else {
-                Err(Error::UnsupportedSketch)
+    fn with_entry<P: Into<PathBuf>>(
+        revindex: RevIndex,
+        path: P,
+    ) -> Result<(TemplateEntry, RevIndex), Error> {
+        let entry = TemplateEntry::from_template(&revindex.template(), path)
+            .map_err(|e| Error::IndexLoading(format!("{}", e)))?;
+        Ok((entry, revindex))
+    }
+
+    /// Find the first index matching the requested template that is also
+    /// compatible with a sketch in `query`.
+    fn select<'a>(
+        &self,
+        query: &'a Signature,
+        params: &TemplateQuery,
+    ) -> Result<(&RevIndex, Cow<'a, KmerMinHash>), Error> {
+        let mut candidates = self
+            .indices
+            .iter()
+            .filter(|(entry, _)| params.matches(entry))
+            .peekable();
+        if candidates.peek().is_none() {
+            return Err(Error::UnknownTemplate);
+        }
+
+        // Sketches with a smaller scaled are downsampled to the index scaled
+        let mut coarser = None;
+        for (_, revindex) in candidates {
+            match select_minhash(std::slice::from_ref(query), &revindex.template()) {
+                Ok(Some((_, mh))) => return Ok((revindex, mh)),
+                Ok(None) => continue,
+                Err(e) => coarser = Some(Error::CoarserSketch(format!("{}", e))),
             }
-        } else {
-            Err(Error::UnsupportedSignature)
         }
+        Err(coarser.unwrap_or(Error::UnsupportedSignature))
+    }
+
+    fn gather(&self, query: Signature, params: &TemplateQuery) -> Result<Vec<GatherResult>, Error> {
+        let (revindex, mh) = self.select(&query, params)?;
+        let counter = revindex.counter_for_query(&mh);
+        Ok(revindex
+            .gather(counter, 0, &mh)
+            .map_err(|e| Error::Gather(format!("{}", e)))?)
     }
 
     fn search(
         &self,
         query: Signature,
+        params: &TemplateQuery,
         similarity: bool,
         threshold: f64,
-    ) -> Result<Vec<String>, Error> {
-        if let Some(sketch) = query.select_sketch(&self.revindex.template()) {
-            if let Sketch::MinHash(mh) = sketch {
-                let counter = self.revindex.counter_for_query(&mh);
-                let threshold = (threshold * mh.size() as f64) as usize;
-                Ok(self
-                    .revindex
-                    .search(counter, similarity, threshold)
-                    .map_err(|e| Error::Gather(format!("{}", e)))?)
-            } else {
-                Err(Error::UnsupportedSketch)
-            }
-        } else {
-            Err(Error::UnsupportedSignature)
-        }
+    ) -> Result<Vec<SearchResult>, Error> {
+        let (revindex, mh) = self.select(&query, params)?;
+        let counter = revindex.counter_for_query(&mh);
+        revindex
+            .search(counter, similarity, threshold, &mh)
+            .map_err(|e| Error::Search(format!("{}", e)))
     }
 }
 
@@ -141,6 +223,19 @@ struct Search {
     signature: String,
 }
 
+/// Search for the signature in the body of a search request.
+async fn run_search(mut req: Request<RevIndexState>) -> tide::Result<Vec<SearchResult>> {
+    let params: TemplateQuery = req.query()?;
+    let Search {
+        similarity,
+        threshold,
+        signature,
+    } = req.body_json().await?;
+    let sig = parse_sig(&signature.as_bytes())?;
+
+    Ok(req.state().search(sig, &params, similarity, threshold)?)
+}
+
 #[async_std::main]
 async fn main() -> tide::Result<()> {
     tide::log::start();
@@ -148,6 +243,7 @@ async fn main() -> tide::Result<()> {
     let Cli {
         index_path,
         from_file,
+        mmap,
         scaled,
         ksize,
     } = Cli::from_args();
@@ -155,6 +251,7 @@ async fn main() -> tide::Result<()> {
     let mut app = tide::with_state(RevIndexState::load(
         index_path,
         from_file,
+        mmap,
         Some(scaled),
         Some(ksize),
     )?);
@@ -162,22 +259,29 @@ async fn main() -> tide::Result<()> {
     app.at("/gather")
         .post(|mut req: Request<RevIndexState>| async move {
             let raw_data = req.body_bytes().await?;
+            let params: TemplateQuery = req.query()?;
             let sig = parse_sig(&raw_data)?;
-            let result = req.state().gather(sig)?;
+            let result = req.state().gather(sig, &params)?;
 
             Ok(Body::from_json(&result)?)
         });
 
+    // Paths of matching signatures, as returned by previous versions
     app.at("/search")
-        .post(|mut req: Request<RevIndexState>| async move {
-            let Search {
-                similarity,
-                threshold,
-                signature,
-            } = req.body_json().await?;
-            let sig = parse_sig(&signature.as_bytes())?;
-
-            let result = req.state().search(sig, similarity, threshold)?;
+        .post(|req: Request<RevIndexState>| async move {
+            let filenames: Vec<String> = run_search(req)
+                .await?
+                .into_iter()
+                .map(|result| result.filename().clone())
+                .collect();
+
+            Ok(Body::from_json(&filenames)?)
+        });
+
+    // Full results, with scores and ANI estimates
+    app.at("/search/results")
+        .post(|req: Request<RevIndexState>| async move {
+            let result = run_search(req).await?;
 
             Ok(Body::from_json(&result)?)
         });